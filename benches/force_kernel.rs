@@ -0,0 +1,234 @@
+//! Compares the original array of structs kernel with the scalar and the vectorised structure
+//! of arrays kernels, the latter also with the half stencil and with neighbour lists. All of
+//! them start from the same particles, integrate the same way and never reorder them.
+//! Run with `cargo bench --bench force_kernel`.
+
+use std::time::{Duration, Instant};
+
+use particle_life::defaults::{
+    default_threads, COLORS, GAME_AREA_SIZE_U, NUM_PARTICLES, REFERENCE_STEP,
+};
+use particle_life::force::{ForceLaw, Triangle};
+use particle_life::grid::Grid;
+use particle_life::particle::{ForceKernel, NeighbourSearch, Particle, ParticleType};
+use particle_life::pool::WorkerPool;
+use particle_life::{Simulation, SimulationParams, Vec2};
+
+const WARMUP_STEPS: usize = 3;
+const STEPS: usize = 20;
+
+/// The kernel from before the particles were stored as a structure of arrays: one `Particle`
+/// per entry, looked up by index for every pair, with the type id and the positions in the
+/// same struct
+struct AosParticles {
+    particles: Vec<Particle>,
+    game_area_size: Vec2,
+    grid: Grid,
+    forces: Vec<Vec2>,
+}
+
+impl AosParticles {
+    fn new(simulation: &Simulation) -> Self {
+        let game_area_size = simulation.world_size();
+        Self {
+            particles: simulation.particles.iter().collect(),
+            game_area_size,
+            grid: Grid::new(game_area_size, simulation.params.max_effect_range()),
+            forces: Vec::new(),
+        }
+    }
+
+    // Adds the force on every particle of `cell` to `forces`, which has one entry per
+    // particle in the cell
+    fn cell_forces(
+        &self,
+        cell: usize,
+        laws: &[Vec<Triangle>],
+        types: &[ParticleType],
+        forces: &mut [Vec2],
+    ) {
+        let cell1 = self.grid.cell(cell);
+        for cell2 in self.grid.neighbour_cells(cell) {
+            for (pi, force) in forces.iter_mut().enumerate() {
+                for &j in self.grid.cell(cell2) {
+                    let i = cell1[pi];
+                    if i == j {
+                        continue;
+                    }
+                    let type1 = self.particles[i].type_id;
+                    let type2 = self.particles[j].type_id;
+
+                    let mut d = self.particles[j].pos - self.particles[i].pos;
+
+                    let size = self.game_area_size;
+                    if d.x.abs() > size.x / 2. {
+                        d.x = -d.x.signum() * (size.x - d.x.abs());
+                    }
+                    if d.y.abs() > size.y / 2. {
+                        d.y = -d.y.signum() * (size.y - d.y.abs());
+                    }
+
+                    let distance = d.length();
+                    d /= distance;
+
+                    if distance < types[type1].max_distance[type2] {
+                        let attraction = types[type1].attraction[type2];
+                        *force += d * laws[type1][type2].magnitude(distance, attraction);
+                    }
+                }
+            }
+        }
+    }
+
+    fn update(&mut self, types: &[ParticleType], params: &SimulationParams, pool: &WorkerPool) {
+        let particles = &self.particles;
+        self.grid
+            .rebuild(particles.len(), |i| particles[i].pos, pool);
+
+        let laws: Vec<Vec<Triangle>> = types
+            .iter()
+            .map(|type1| {
+                let radii = type1.min_distance.iter().zip(&type1.max_distance);
+                radii
+                    .map(|(&min_distance, &max_distance)| {
+                        Triangle::new(params, min_distance, max_distance)
+                    })
+                    .collect()
+            })
+            .collect();
+
+        let mut forces = std::mem::take(&mut self.forces);
+        forces.clear();
+        forces.resize(self.particles.len(), Vec2::ZERO);
+
+        let num_cells = self.grid.num_cells();
+        let cells_per_task = num_cells.div_ceil(pool.threads()).max(1);
+        let cell_start = self.grid.cell_start();
+        let mut rest = forces.as_mut_slice();
+        let chunks = (0..num_cells).step_by(cells_per_task).map(|first| {
+            let cells = first..(first + cells_per_task).min(num_cells);
+            let len = cell_start[cells.end] - cell_start[cells.start];
+            let (chunk_forces, tail) = std::mem::take(&mut rest).split_at_mut(len);
+            rest = tail;
+            (cells, chunk_forces)
+        });
+        let this = &*self;
+        pool.for_each(chunks, |(cells, chunk_forces)| {
+            let mut offset = 0;
+            for cell in cells {
+                let len = this.grid.cell(cell).len();
+                this.cell_forces(cell, &laws, types, &mut chunk_forces[offset..offset + len]);
+                offset += len;
+            }
+        });
+
+        // Semi-implicit Euler with the same force scale and friction as the simulation's
+        // default integrator, so that both runs stay alike
+        let dt = params.dt;
+        let force_scale: Vec<f32> = types
+            .iter()
+            .map(|type1| 1. / (REFERENCE_STEP * REFERENCE_STEP * type1.mass))
+            .collect();
+        let decay: Vec<f32> = types
+            .iter()
+            .map(|type1| (-type1.friction.unwrap_or(params.friction) * dt).exp())
+            .collect();
+        for (&i, force) in self.grid.particle_index().iter().zip(&forces) {
+            let particle = &mut self.particles[i];
+            particle.vel += *force * force_scale[particle.type_id] * dt;
+        }
+        self.forces = forces;
+
+        let size = self.game_area_size;
+        for particle in &mut self.particles {
+            particle.pos += particle.vel * dt;
+            // Wrap around
+            if particle.pos.x < 0. {
+                particle.pos.x = size.x;
+            } else if particle.pos.x >= size.x {
+                particle.pos.x = 0.;
+            }
+            if particle.pos.y < 0. {
+                particle.pos.y = size.y;
+            } else if particle.pos.y >= size.y {
+                particle.pos.y = 0.;
+            }
+            particle.vel *= decay[particle.type_id];
+        }
+    }
+}
+
+fn print_time(num_particles: usize, name: &str, per_step: Duration) {
+    println!(
+        "{:>8} particles  {:<20} {:>8.2} ms/step",
+        num_particles,
+        name,
+        per_step.as_secs_f64() * 1000.
+    );
+}
+
+fn main() {
+    let threads = default_threads();
+    println!("{} threads, {} steps each", threads, STEPS);
+
+    // Same density as the default world
+    for scale in [1., 2.] {
+        let num_particles = (NUM_PARTICLES as f32 * scale * scale) as usize;
+
+        let simulation = Simulation::new(
+            GAME_AREA_SIZE_U * scale,
+            num_particles,
+            &COLORS,
+            SimulationParams::default(),
+            0,
+        );
+        let pool = WorkerPool::new(threads, true);
+        let mut baseline = AosParticles::new(&simulation);
+        for _ in 0..WARMUP_STEPS {
+            baseline.update(&simulation.types, &simulation.params, &pool);
+        }
+        let start = Instant::now();
+        for _ in 0..STEPS {
+            baseline.update(&simulation.types, &simulation.params, &pool);
+        }
+        print_time(num_particles, "AoS", start.elapsed() / STEPS as u32);
+
+        let variants = [
+            (ForceKernel::Scalar, false, NeighbourSearch::Grid),
+            (ForceKernel::Simd, false, NeighbourSearch::Grid),
+            (ForceKernel::Simd, true, NeighbourSearch::Grid),
+            (ForceKernel::Simd, false, NeighbourSearch::Verlet),
+        ];
+        for (kernel, half_stencil, neighbour_search) in variants {
+            let mut simulation = Simulation::new(
+                GAME_AREA_SIZE_U * scale,
+                num_particles,
+                &COLORS,
+                SimulationParams::default(),
+                0,
+            );
+            simulation.set_threads(threads, true);
+            simulation.particles.set_kernel(kernel);
+            // The baseline never reorders, see the reorder bench for what that is worth
+            simulation.particles.set_reorder_interval(0);
+            simulation.particles.set_half_stencil(half_stencil);
+            simulation.particles.set_neighbour_search(neighbour_search);
+
+            for _ in 0..WARMUP_STEPS {
+                simulation.step();
+            }
+            let start = Instant::now();
+            for _ in 0..STEPS {
+                simulation.step();
+            }
+            let per_step = start.elapsed() / STEPS as u32;
+
+            let name = match (half_stencil, neighbour_search) {
+                (true, _) => format!("{:?}, half stencil", kernel),
+                (_, NeighbourSearch::Verlet) => format!("{:?}, Verlet", kernel),
+                _ => format!("{:?}", kernel),
+            };
+            print_time(num_particles, &name, per_step);
+        }
+    }
+}
@@ -0,0 +1,51 @@
+//! Step time with and without sorting the particles along a Z-order curve.
+//! Run with `cargo bench --bench reorder`.
+
+use std::time::Instant;
+
+use particle_life::defaults::{default_threads, COLORS, GAME_AREA_SIZE_U, NUM_PARTICLES};
+use particle_life::{Simulation, SimulationParams};
+
+const WARMUP_STEPS: usize = 3;
+const STEPS: usize = 20;
+
+fn main() {
+    let threads = default_threads();
+    println!("{} threads, {} steps each", threads, STEPS);
+
+    // Same density as the default world, particles start out in random order
+    for scale in [1., 2., 3.] {
+        let num_particles = (NUM_PARTICLES as f32 * scale * scale) as usize;
+        for interval in [0, 16] {
+            let mut simulation = Simulation::new(
+                GAME_AREA_SIZE_U * scale,
+                num_particles,
+                &COLORS,
+                SimulationParams::default(),
+                0,
+            );
+            simulation.set_threads(threads, true);
+            simulation.particles.set_reorder_interval(interval);
+
+            for _ in 0..WARMUP_STEPS {
+                simulation.step();
+            }
+            let start = Instant::now();
+            for _ in 0..STEPS {
+                simulation.step();
+            }
+            let per_step = start.elapsed() / STEPS as u32;
+
+            let name = match interval {
+                0 => "spawn order".to_owned(),
+                _ => format!("reordered every {}", interval),
+            };
+            println!(
+                "{:>8} particles  {:<20} {:>8.2} ms/step",
+                num_particles,
+                name,
+                per_step.as_secs_f64() * 1000.
+            );
+        }
+    }
+}
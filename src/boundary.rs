@@ -0,0 +1,60 @@
+//! What happens to particles at the edges of the world, chosen separately for each axis.
+
+use glam::Vec2;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BoundaryMode {
+    /// Particles leaving on one side come back on the other, and interact across the edge
+    #[default]
+    Periodic,
+    /// Particles bounce off the edges without losing speed
+    Reflect,
+    /// Particles leaving the world are removed
+    Absorb,
+    /// The edges repel particles like another particle would at the same distance.
+    /// Particles that still get past them are stopped at the edge.
+    SoftWall,
+}
+
+impl BoundaryMode {
+    pub fn is_periodic(self) -> bool {
+        self == BoundaryMode::Periodic
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Boundary {
+    pub x: BoundaryMode,
+    pub y: BoundaryMode,
+}
+
+impl Boundary {
+    pub fn new(x: BoundaryMode, y: BoundaryMode) -> Self {
+        Self { x, y }
+    }
+
+    /// Same mode on both axes
+    pub fn uniform(mode: BoundaryMode) -> Self {
+        Self::new(mode, mode)
+    }
+
+    pub fn periodic(&self) -> [bool; 2] {
+        [self.x.is_periodic(), self.y.is_periodic()]
+    }
+
+    /// `size` along the periodic axes and zero along the others, which turns the minimum
+    /// image offset into the plain offset
+    pub fn wrap_size(&self, size: Vec2) -> Vec2 {
+        Vec2::new(
+            if self.x.is_periodic() { size.x } else { 0. },
+            if self.y.is_periodic() { size.y } else { 0. },
+        )
+    }
+
+    pub fn absorbs(&self) -> bool {
+        self.x == BoundaryMode::Absorb || self.y == BoundaryMode::Absorb
+    }
+}
@@ -0,0 +1,102 @@
+use clap::Parser;
+use macroquad::prelude::Vec2;
+use particle_life::defaults::MAX_TYPES;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use crate::config::Config;
+
+/// Options left out fall back to the config files.
+#[derive(Parser, Debug)]
+#[command(version, about = "Particle life simulation")]
+pub struct Args {
+    /// Number of particles
+    #[arg(long)]
+    particles: Option<usize>,
+
+    /// World size in units, e.g. 8000x8000
+    #[arg(long, value_parser = parse_size)]
+    world: Option<Vec2>,
+
+    /// Number of particle types (at most 256), ignored when a preset is given
+    #[arg(long, value_parser = parse_type_count)]
+    types: Option<usize>,
+
+    /// Seed for types and particles, taken from the clock when not given
+    #[arg(long)]
+    pub seed: Option<u64>,
+
+    /// Worker threads for the force pass
+    #[arg(long, value_parser = parse_positive)]
+    threads: Option<usize>,
+
+    /// Let the OS schedule the worker threads instead of pinning them to cores
+    #[arg(long)]
+    no_pin: bool,
+
+    /// Preset file (.toml or .json) to start with
+    #[arg(long)]
+    pub preset: Option<PathBuf>,
+
+    /// Window size in pixels, e.g. 1400x800
+    #[arg(long, value_parser = parse_size)]
+    window: Option<Vec2>,
+}
+
+impl Args {
+    /// Writes every given option into `config`
+    pub fn apply(&self, config: &mut Config) {
+        if let Some(particles) = self.particles {
+            config.simulation.particles = particles;
+        }
+        if let Some(world) = self.world {
+            config.simulation.world_size = world.to_array();
+        }
+        if let Some(types) = self.types {
+            config.simulation.types = types;
+        }
+        if let Some(threads) = self.threads {
+            config.simulation.threads = threads;
+        }
+        if self.no_pin {
+            config.simulation.pin_threads = false;
+        }
+        if let Some(window) = self.window {
+            config.window.size = window.to_array();
+        }
+    }
+}
+
+// Parsed once, `window_config` needs them before `main` runs
+pub fn args() -> &'static Args {
+    static ARGS: OnceLock<Args> = OnceLock::new();
+    ARGS.get_or_init(Args::parse)
+}
+
+fn parse_positive(value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(format!("'{}' is not a positive integer", value)),
+    }
+}
+
+fn parse_type_count(value: &str) -> Result<usize, String> {
+    match parse_positive(value)? {
+        n if n <= MAX_TYPES => Ok(n),
+        _ => Err(format!("there can be at most {} types", MAX_TYPES)),
+    }
+}
+
+fn parse_size(value: &str) -> Result<Vec2, String> {
+    let (x, y) = value
+        .split_once('x')
+        .ok_or_else(|| format!("expected WIDTHxHEIGHT, got '{}'", value))?;
+    let parse = |v: &str| -> Result<f32, String> {
+        match v.trim().parse::<f32>() {
+            Ok(v) if v.is_finite() && v > 0. => Ok(v),
+            _ => Err(format!("'{}' is not a positive number", v)),
+        }
+    };
+
+    Ok(Vec2::new(parse(x)?, parse(y)?))
+}
@@ -1,23 +1,455 @@
-use macroquad::prelude::*;
+//! Settings loaded at startup from layered TOML files.
+//!
+//! Later layers override earlier ones key by key:
+//! 1. built-in defaults
+//! 2. user config, `<config dir>/particle-life/config.toml`
+//! 3. project config, `particle-life.toml` in the working directory
+//!
+//! Command line options override all of them.
 
-pub static MAX_CORES: usize = 8;
+use particle_life::boundary::Boundary;
+use particle_life::defaults::*;
+use particle_life::domain::{Domain, DomainShape};
+use particle_life::params::per_second_friction;
+use particle_life::particle::NeighbourSearch;
+use particle_life::SimulationParams;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::time::SystemTime;
 
-pub static WINDOW_SIZE_PX: Vec2 = Vec2::from_array([1400., 800.]);
-pub static GAME_AREA_SIZE_U: Vec2 = Vec2::from_array([8000., 8000.]); // Might act weird when this is not square
-pub static MENU_AREA_SIZE_PX: Vec2 = Vec2::from_array([500., WINDOW_SIZE_PX.y]);
+pub static PROJECT_CONFIG_FILE: &str = "particle-life.toml";
 
-pub static MIN_DISTANCE: f32 = 10.; // Distance at which particles start to repel each other regardless of their attraction
-pub static MAX_DISTNACE: f32 = 80.; // Distance at which particles stop having an effect on each other
-pub static mut REPEL_CONSTANT: f32 = 2.;
-pub static mut ATTRACT_CONSTANT: f32 = 0.05;
-pub static NUM_PARTICLES: usize = 100_000;
-pub static PARTICLE_RADIUS: f32 = 3.;
-pub static mut PARTICLE_FRICTION: f32 = 0.15;
-pub static COLORS: [Color; 7] = [RED, ORANGE, YELLOW, WHITE, GREEN, BLUE, VIOLET];
-// pub static COLORS: [Color; 4] = [RED, GREEN, BLUE, YELLOW];
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub window: WindowConfig,
+    pub camera: CameraConfig,
+    pub menu: MenuConfig,
+    pub simulation: SimulationConfig,
+    pub physics: SimulationParams,
+    pub files: FilesConfig,
+}
 
-pub static CAMERA_DRAG_SPEED: f32 = 750.;
-pub static CAMERA_ZOOM_SPEED: f32 = 0.1;
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WindowConfig {
+    pub size: [f32; 2],
+}
 
-pub static CHANGE_TYPE_ATTRACTION_SPEED: f32 = 0.02;
-pub static MENU_BACKGORUND_COLOR: Color = DARKGRAY;
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CameraConfig {
+    pub drag_speed: f32,
+    pub zoom_speed: f32,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MenuConfig {
+    pub width: f32,
+    pub background_color: [f32; 4],
+    pub change_type_attraction_speed: f32,
+    pub change_radius_speed: f32, // Change of a min or max radius per frame a button is held
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SimulationConfig {
+    pub particles: usize,
+    pub world_size: [f32; 2],
+    pub boundary: Boundary, // What happens at the edges of the world, per axis
+    pub domain: DomainShape, // Region inside the world the particles are confined to
+    pub types: usize,
+    pub threads: usize,
+    pub pin_threads: bool, // Pin every worker thread to its own core where the system allows it
+    pub half_stencil: bool, // Visit every pair of neighbours once, see `Particles::set_half_stencil`
+    pub reorder_interval: usize, // Updates between sorting the particles in memory, 0 never sorts
+    pub neighbour_search: NeighbourSearch,
+    pub verlet_skin: f32, // Extra distance kept in the neighbour lists, see `Particles::set_verlet_skin`
+    pub steps_per_second: f32, // Target rate of the simulation thread, see `runner::Rate`
+    pub substeps: usize,  // Calls to `Simulation::step` per step of the simulation thread
+    pub palette: Vec<[f32; 4]>, // Colors of the types in order, cycled through with new hues when there are more types
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FilesConfig {
+    pub snapshot: PathBuf,
+    pub presets_dir: PathBuf,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            window: WindowConfig {
+                size: [1400., 800.],
+            },
+            camera: CameraConfig {
+                drag_speed: 750.,
+                zoom_speed: 0.1,
+            },
+            menu: MenuConfig {
+                width: 500.,
+                background_color: [0.31, 0.31, 0.31, 1.00], // macroquad's DARKGRAY
+                change_type_attraction_speed: 0.02,
+                change_radius_speed: 0.5,
+            },
+            simulation: SimulationConfig {
+                particles: NUM_PARTICLES,
+                world_size: GAME_AREA_SIZE_U.to_array(),
+                boundary: Boundary::default(),
+                domain: DomainShape::default(),
+                types: COLORS.len(),
+                threads: default_threads(),
+                pin_threads: true,
+                half_stencil: false,
+                reorder_interval: 16,
+                neighbour_search: NeighbourSearch::Grid,
+                verlet_skin: 16.,
+                steps_per_second: 60.,
+                substeps: 1,
+                palette: COLORS.to_vec(),
+            },
+            physics: SimulationParams::default(),
+            files: FilesConfig {
+                snapshot: "snapshot.plsn".into(),
+                presets_dir: "presets".into(),
+            },
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, String),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, err) => write!(f, "could not read {}: {}", path.display(), err),
+            ConfigError::Parse(path, err) => write!(f, "error in {}: {}", path.display(), err),
+            ConfigError::Invalid(reason) => write!(f, "invalid config: {}", reason),
+        }
+    }
+}
+
+impl Config {
+    /// Config files in the order they are applied
+    pub fn layer_paths() -> Vec<PathBuf> {
+        let mut paths = Vec::new();
+        if let Some(dir) = dirs::config_dir() {
+            paths.push(dir.join("particle-life").join("config.toml"));
+        }
+        paths.push(PathBuf::from(PROJECT_CONFIG_FILE));
+        paths
+    }
+
+    /// Defaults overridden by every config layer that exists. Not validated yet, as the
+    /// command line options still go on top, see `validate`.
+    pub fn load() -> Result<Self, ConfigError> {
+        let mut merged =
+            toml::Table::try_from(Config::default()).expect("default config always serializes");
+
+        for path in Self::layer_paths() {
+            let text = match fs::read_to_string(&path) {
+                Ok(text) => text,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(ConfigError::Read(path, err)),
+            };
+            let mut layer: toml::Table = text.parse().map_err(|err: toml::de::Error| {
+                ConfigError::Parse(path.clone(), err.to_string())
+            })?;
+            migrate_friction(&mut layer).map_err(|reason| {
+                ConfigError::Parse(path.clone(), format!("physics: {}", reason))
+            })?;
+            merge(&mut merged, layer);
+
+            // Deserialized after every layer so that type errors point at the right file
+            Config::deserialize(merged.clone())
+                .map_err(|err| ConfigError::Parse(path.clone(), err.to_string()))?;
+        }
+
+        Config::deserialize(merged)
+            .map_err(|err| ConfigError::Parse(PathBuf::from("<defaults>"), err.to_string()))
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |key: &str, value: &dyn fmt::Debug, expected: &str| {
+            Err(ConfigError::Invalid(format!(
+                "{} is {:?}, expected {}",
+                key, value, expected
+            )))
+        };
+        let positive = |value: f32| value.is_finite() && value > 0.;
+        let is_color = |color: &[f32; 4]| color.iter().all(|c| (0.0..=1.0).contains(c));
+
+        if !self.window.size.iter().all(|&v| positive(v)) {
+            return invalid("window.size", &self.window.size, "two positive numbers");
+        }
+        if !positive(self.camera.drag_speed) {
+            return invalid(
+                "camera.drag_speed",
+                &self.camera.drag_speed,
+                "a positive number",
+            );
+        }
+        if !(positive(self.camera.zoom_speed) && self.camera.zoom_speed <= 1.) {
+            return invalid(
+                "camera.zoom_speed",
+                &self.camera.zoom_speed,
+                "a number in (0, 1]",
+            );
+        }
+        if !positive(self.menu.width) {
+            return invalid("menu.width", &self.menu.width, "a positive number");
+        }
+        if !is_color(&self.menu.background_color) {
+            return invalid(
+                "menu.background_color",
+                &self.menu.background_color,
+                "four numbers in [0, 1]",
+            );
+        }
+        let speed = self.menu.change_type_attraction_speed;
+        if !(positive(speed) && speed <= 2.) {
+            return invalid(
+                "menu.change_type_attraction_speed",
+                &speed,
+                "a number in (0, 2]",
+            );
+        }
+        if !positive(self.menu.change_radius_speed) {
+            return invalid(
+                "menu.change_radius_speed",
+                &self.menu.change_radius_speed,
+                "a positive number",
+            );
+        }
+        if !self.simulation.world_size.iter().all(|&v| positive(v)) {
+            return invalid(
+                "simulation.world_size",
+                &self.simulation.world_size,
+                "two positive numbers",
+            );
+        }
+        let domain = Domain::load(&self.simulation.domain)
+            .map_err(|err| ConfigError::Invalid(format!("simulation.domain: {}", err)))?;
+        if !domain.fits(self.simulation.world_size.into()) {
+            return invalid(
+                "simulation.domain",
+                &self.simulation.domain,
+                "a domain with room for particles, an annulus has to leave a gap in the middle of every side",
+            );
+        }
+        if !(1..=MAX_TYPES).contains(&self.simulation.types) {
+            return invalid(
+                "simulation.types",
+                &self.simulation.types,
+                &format!("a number in [1, {}]", MAX_TYPES),
+            );
+        }
+        if self.simulation.threads == 0 {
+            return invalid("simulation.threads", &self.simulation.threads, "at least 1");
+        }
+        if !positive(self.simulation.verlet_skin) {
+            return invalid(
+                "simulation.verlet_skin",
+                &self.simulation.verlet_skin,
+                "a positive number",
+            );
+        }
+        if !positive(self.simulation.steps_per_second) {
+            return invalid(
+                "simulation.steps_per_second",
+                &self.simulation.steps_per_second,
+                "a positive number",
+            );
+        }
+        if self.simulation.substeps == 0 {
+            return invalid(
+                "simulation.substeps",
+                &self.simulation.substeps,
+                "at least 1",
+            );
+        }
+        if self.simulation.palette.is_empty() {
+            return invalid(
+                "simulation.palette",
+                &self.simulation.palette,
+                "at least one color",
+            );
+        }
+        if let Some(color) = self.simulation.palette.iter().find(|c| !is_color(c)) {
+            return invalid(
+                "a color in simulation.palette",
+                color,
+                "four numbers in [0, 1]",
+            );
+        }
+        self.physics
+            .validate()
+            .map_err(|reason| ConfigError::Invalid(format!("physics: {}", reason)))?;
+
+        Ok(())
+    }
+
+    pub fn type_color(&self, index: usize) -> [f32; 4] {
+        type_color(&self.simulation.palette, index)
+    }
+}
+
+// A layer that sets `physics.friction` without `physics.dt` is from before time steps were
+// configurable, its friction is converted like `SimulationParams` files are
+fn migrate_friction(layer: &mut toml::Table) -> Result<(), String> {
+    let physics = match layer.get_mut("physics") {
+        Some(toml::Value::Table(physics)) => physics,
+        _ => return Ok(()),
+    };
+    if physics.contains_key("dt") {
+        return Ok(());
+    }
+    if let Some(friction) = physics.get_mut("friction") {
+        let per_frame = match friction {
+            toml::Value::Float(value) => *value as f32,
+            toml::Value::Integer(value) => *value as f32,
+            _ => return Ok(()), // Left for deserializing to report
+        };
+        *friction = toml::Value::Float(per_second_friction(per_frame)? as f64);
+    }
+    Ok(())
+}
+
+// Recursively overrides values in `base` with the ones in `layer`
+fn merge(base: &mut toml::Table, layer: toml::Table) {
+    for (key, value) in layer {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base_table)), toml::Value::Table(layer_table)) => {
+                merge(base_table, layer_table)
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Watches the config layers and reloads them when one of them changes on disk.
+pub struct ConfigWatcher {
+    modified: Vec<Option<SystemTime>>,
+}
+
+impl ConfigWatcher {
+    pub fn new() -> Self {
+        Self {
+            modified: Self::modification_times(),
+        }
+    }
+
+    fn modification_times() -> Vec<Option<SystemTime>> {
+        Config::layer_paths()
+            .iter()
+            .map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+            .collect()
+    }
+
+    /// Returns the reloaded config if a layer was changed, created or removed since the last poll
+    pub fn poll(&mut self) -> Option<Result<Config, ConfigError>> {
+        let modified = Self::modification_times();
+        if modified == self.modified {
+            return None;
+        }
+        self.modified = modified;
+
+        Some(Config::load())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn defaults() -> toml::Table {
+        toml::Table::try_from(Config::default()).unwrap()
+    }
+
+    fn layer(text: &str) -> toml::Table {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn layers_override_single_keys() {
+        let mut merged = defaults();
+        merge(&mut merged, layer("[physics]\nrepel_constant = 3.5\n"));
+        merge(&mut merged, layer("[menu]\nwidth = 420.0\n"));
+        let config = Config::deserialize(merged).unwrap();
+
+        let default = Config::default();
+        assert_eq!(config.physics.repel_constant, 3.5);
+        assert_eq!(config.menu.width, 420.);
+        // Siblings of the overridden keys keep their values
+        assert_eq!(config.physics.friction, default.physics.friction);
+        assert_eq!(config.physics.dt, default.physics.dt);
+        assert_eq!(config.menu.background_color, default.menu.background_color);
+        assert_eq!(config.simulation.particles, default.simulation.particles);
+    }
+
+    #[test]
+    fn later_layers_win() {
+        let mut merged = defaults();
+        merge(
+            &mut merged,
+            layer("[camera]\nzoom_speed = 0.2\ndrag_speed = 500.0\n"),
+        );
+        merge(&mut merged, layer("[camera]\nzoom_speed = 0.3\n"));
+        let config = Config::deserialize(merged).unwrap();
+
+        assert_eq!(config.camera.zoom_speed, 0.3);
+        assert_eq!(config.camera.drag_speed, 500.);
+    }
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        assert!(Config::default().validate().is_ok());
+
+        let mut config = Config::default();
+        config.menu.width = -1.;
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+
+        let mut config = Config::default();
+        config.physics.max_distance = config.physics.min_distance / 2.;
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+
+        let mut config = Config::default();
+        config.simulation.palette[0] = [1.5, 0., 0., 1.];
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+
+        let mut config = Config::default();
+        config.simulation.types = 0;
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+
+        // Unknown keys are typos, not settings
+        let mut merged = defaults();
+        merge(&mut merged, layer("[menu]\nwidht = 420.0\n"));
+        assert!(Config::deserialize(merged).is_err());
+    }
+
+    #[test]
+    fn friction_without_dt_is_per_frame() {
+        let mut old = layer("[physics]\nfriction = 0.15\n");
+        migrate_friction(&mut old).unwrap();
+        let friction = old["physics"]["friction"].as_float().unwrap() as f32;
+        assert_eq!(friction, per_second_friction(0.15).unwrap());
+
+        let mut new = layer("[physics]\nfriction = 9.75\ndt = 0.01\n");
+        migrate_friction(&mut new).unwrap();
+        assert_eq!(new["physics"]["friction"].as_float(), Some(9.75));
+
+        let mut ambiguous = layer("[physics]\nfriction = 9.75\n");
+        assert!(migrate_friction(&mut ambiguous).is_err());
+    }
+}
@@ -0,0 +1,54 @@
+use glam::Vec2;
+
+pub static MAX_CORES: usize = 8;
+
+/// Worker threads used for the force pass unless configured otherwise
+pub fn default_threads() -> usize {
+    (num_cpus::get().min(MAX_CORES) - 1).max(1)
+}
+
+pub static GAME_AREA_SIZE_U: Vec2 = Vec2::from_array([8000., 8000.]);
+
+pub static MIN_DISTANCE: f32 = 10.; // Distance at which particles start to repel each other regardless of their attraction
+pub static MAX_DISTNACE: f32 = 80.; // Distance at which particles stop having an effect on each other
+pub static REPEL_CONSTANT: f32 = 2.;
+pub static ATTRACT_CONSTANT: f32 = 0.05;
+pub static NUM_PARTICLES: usize = 100_000;
+pub static MAX_TYPES: usize = 256; // Type ids are stored in a byte
+pub static PARTICLE_RADIUS: f32 = 3.;
+pub static PARTICLE_FRICTION: f32 = 9.75; // Per second, loses 15% of the speed every 1/60 s
+pub static DT: f32 = 1. / 60.;
+// Forces are scaled so that steps of this length match the one update per frame at 60 FPS
+// the constants above were tuned with
+pub static REFERENCE_STEP: f32 = 1. / 60.;
+
+// RGBA, same values as macroquad's RED, ORANGE, YELLOW, WHITE, GREEN, BLUE and VIOLET
+pub static COLORS: [[f32; 4]; 7] = [
+    [0.90, 0.16, 0.22, 1.00],
+    [1.00, 0.63, 0.00, 1.00],
+    [0.99, 0.98, 0.00, 1.00],
+    [1.00, 1.00, 1.00, 1.00],
+    [0.00, 0.89, 0.19, 1.00],
+    [0.00, 0.47, 0.95, 1.00],
+    [0.53, 0.24, 0.75, 1.00],
+];
+
+/// Color for the type at `index`, continuing with evenly spread hues once `palette` runs out
+pub fn type_color(palette: &[[f32; 4]], index: usize) -> [f32; 4] {
+    if index < palette.len() {
+        return palette[index];
+    }
+
+    // Golden angle steps keep consecutive hues far apart
+    let hue = ((index - palette.len()) as f32 * 137.508).rem_euclid(360.) / 60.;
+    let x = 1. - (hue % 2. - 1.).abs();
+    let (r, g, b) = match hue as usize {
+        0 => (1., x, 0.),
+        1 => (x, 1., 0.),
+        2 => (0., 1., x),
+        3 => (0., x, 1.),
+        4 => (x, 0., 1.),
+        _ => (1., 0., x),
+    };
+    [r, g, b, 1.]
+}
@@ -0,0 +1,343 @@
+//! The region of the world particles are confined to.
+//!
+//! Shapes are centred in the world, a mask image is stretched over all of it. Particles
+//! that leave the domain are reflected back at its edge.
+
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+
+use glam::Vec2;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// Describes a domain, e.g. in a config file. Masks are only read by `Domain::load`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "shape", rename_all = "snake_case", deny_unknown_fields)]
+pub enum DomainShape {
+    /// The whole world
+    #[default]
+    Rect,
+    Disk {
+        radius: f32,
+    },
+    Annulus {
+        inner_radius: f32,
+        outer_radius: f32,
+    },
+    /// Black and white PNG, particles stay on the white pixels
+    Mask {
+        path: PathBuf,
+    },
+}
+
+#[derive(Debug)]
+pub enum DomainError {
+    Io(PathBuf, io::Error),
+    Png(PathBuf, png::DecodingError),
+    EmptyMask(PathBuf),
+    Invalid(String),
+}
+
+impl fmt::Display for DomainError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DomainError::Io(path, err) => write!(f, "could not read {}: {}", path.display(), err),
+            DomainError::Png(path, err) => {
+                write!(f, "could not decode {}: {}", path.display(), err)
+            }
+            DomainError::EmptyMask(path) => {
+                write!(
+                    f,
+                    "{} has no white pixels to place particles on",
+                    path.display()
+                )
+            }
+            DomainError::Invalid(reason) => write!(f, "invalid domain: {}", reason),
+        }
+    }
+}
+
+impl Error for DomainError {}
+
+/// Pixels of a mask image, `true` where particles may be
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mask {
+    width: usize,
+    height: usize,
+    inside: Vec<bool>,
+    white: Vec<usize>, // Indices of the pixels that are inside, to place particles on
+}
+
+impl Mask {
+    /// Reads a PNG, every pixel brighter than mid grey and not mostly transparent is inside
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, DomainError> {
+        let path = path.as_ref();
+        let file = File::open(path).map_err(|err| DomainError::Io(path.into(), err))?;
+        let png_err = |err| DomainError::Png(path.into(), err);
+
+        let mut decoder = png::Decoder::new(BufReader::new(file));
+        decoder.set_transformations(png::Transformations::normalize_to_color8());
+        let mut reader = decoder.read_info().map_err(png_err)?;
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut pixels).map_err(png_err)?;
+
+        let samples = info.color_type.samples();
+        let has_alpha = matches!(
+            info.color_type,
+            png::ColorType::GrayscaleAlpha | png::ColorType::Rgba
+        );
+        let colors = if has_alpha { samples - 1 } else { samples };
+        let inside: Vec<bool> = pixels[..info.buffer_size()]
+            .chunks_exact(samples)
+            .map(|pixel| {
+                let brightness =
+                    pixel[..colors].iter().map(|&c| c as u32).sum::<u32>() / colors as u32;
+                let opaque = !has_alpha || pixel[samples - 1] >= 128;
+                brightness >= 128 && opaque
+            })
+            .collect();
+        Self::from_pixels(info.width as usize, info.height as usize, inside)
+            .ok_or_else(|| DomainError::EmptyMask(path.into()))
+    }
+
+    /// Mask of `width` by `height` pixels, row by row from the top. `None` if `inside` has
+    /// the wrong length or no pixel is inside.
+    pub fn from_pixels(width: usize, height: usize, inside: Vec<bool>) -> Option<Self> {
+        let white: Vec<usize> = (0..inside.len()).filter(|&i| inside[i]).collect();
+        if inside.len() != width * height || white.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            width,
+            height,
+            inside,
+            white,
+        })
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Pixels in the order `from_pixels` takes them
+    pub fn pixels(&self) -> &[bool] {
+        &self.inside
+    }
+
+    // Uniformly distributed over the white pixels
+    fn random_position(&self, rng: &mut impl Rng, world_size: Vec2) -> Vec2 {
+        let pixel_size = world_size / Vec2::new(self.width as f32, self.height as f32);
+        loop {
+            let pixel = self.white[rng.gen_range(0..self.white.len())];
+            let corner =
+                Vec2::new((pixel % self.width) as f32, (pixel / self.width) as f32) * pixel_size;
+            let pos = corner + Vec2::new(rng.gen(), rng.gen()) * pixel_size;
+            // Rounding can put it on a neighbouring pixel
+            if self.contains(pos, world_size) {
+                return pos;
+            }
+        }
+    }
+
+    fn contains(&self, pos: Vec2, world_size: Vec2) -> bool {
+        let x = (pos.x / world_size.x * self.width as f32).floor();
+        let y = (pos.y / world_size.y * self.height as f32).floor();
+        if x < 0. || y < 0. {
+            return false;
+        }
+        let (x, y) = (x as usize, y as usize);
+
+        x < self.width && y < self.height && self.inside[y * self.width + x]
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum Domain {
+    #[default]
+    Rect,
+    Disk {
+        radius: f32,
+    },
+    Annulus {
+        inner_radius: f32,
+        outer_radius: f32,
+    },
+    Mask(Mask),
+}
+
+impl Domain {
+    /// Checks the shape and reads its mask, if it has one
+    pub fn load(shape: &DomainShape) -> Result<Self, DomainError> {
+        let positive = |value: f32| value.is_finite() && value > 0.;
+        match *shape {
+            DomainShape::Rect => Ok(Domain::Rect),
+            DomainShape::Disk { radius } => {
+                if !positive(radius) {
+                    return Err(DomainError::Invalid(format!(
+                        "the radius of a disk has to be positive, got {}",
+                        radius
+                    )));
+                }
+                Ok(Domain::Disk { radius })
+            }
+            DomainShape::Annulus {
+                inner_radius,
+                outer_radius,
+            } => {
+                if !(positive(inner_radius)
+                    && positive(outer_radius)
+                    && inner_radius < outer_radius)
+                {
+                    return Err(DomainError::Invalid(format!(
+                        "radii of an annulus have to satisfy 0 < inner_radius < outer_radius, got {} and {}",
+                        inner_radius, outer_radius
+                    )));
+                }
+                Ok(Domain::Annulus {
+                    inner_radius,
+                    outer_radius,
+                })
+            }
+            DomainShape::Mask { ref path } => Ok(Domain::Mask(Mask::load(path)?)),
+        }
+    }
+
+    /// Whether some of a world of `world_size` is inside the domain. Annuli have to leave room
+    /// along both axes, so that particles can be placed by trial and error.
+    pub fn fits(&self, world_size: Vec2) -> bool {
+        match self {
+            Domain::Annulus { inner_radius, .. } => *inner_radius < world_size.min_element() / 2.,
+            _ => true,
+        }
+    }
+
+    /// Uniformly distributed over the part of the domain inside of the world. Only tries
+    /// positions in or around the domain, so small domains don't take longer.
+    pub fn random_position(&self, rng: &mut impl Rng, world_size: Vec2) -> Vec2 {
+        let in_world = |pos: Vec2| pos.cmpge(Vec2::ZERO).all() && pos.cmplt(world_size).all();
+        let (inner_radius, outer_radius) = match self {
+            Domain::Rect => {
+                return Vec2::new(
+                    rng.gen_range(0.0..world_size.x),
+                    rng.gen_range(0.0..world_size.y),
+                )
+            }
+            Domain::Mask(mask) => return mask.random_position(rng, world_size),
+            Domain::Disk { radius } => (0., *radius),
+            Domain::Annulus {
+                inner_radius,
+                outer_radius,
+            } => (*inner_radius, *outer_radius),
+        };
+
+        // Uniform over the ring, cut off at the corners of the world
+        let center = world_size / 2.;
+        let outer_radius = outer_radius.min(center.length());
+        let inner_radius = inner_radius.min(outer_radius);
+        loop {
+            let squared = rng.gen_range(inner_radius.powi(2)..=outer_radius.powi(2));
+            let angle = rng.gen_range(0.0..std::f32::consts::TAU);
+            let pos = center + squared.sqrt() * Vec2::new(angle.cos(), angle.sin());
+            if in_world(pos) && self.contains(pos, world_size) {
+                return pos;
+            }
+        }
+    }
+
+    pub fn is_rect(&self) -> bool {
+        *self == Domain::Rect
+    }
+
+    /// Whether particles may be at `pos` in a world of `world_size`
+    pub fn contains(&self, pos: Vec2, world_size: Vec2) -> bool {
+        let distance = (pos - world_size / 2.).length();
+        match self {
+            Domain::Rect => true,
+            Domain::Disk { radius } => distance <= *radius,
+            Domain::Annulus {
+                inner_radius,
+                outer_radius,
+            } => (*inner_radius..=*outer_radius).contains(&distance),
+            Domain::Mask(mask) => mask.contains(pos, world_size),
+        }
+    }
+
+    /// Moves a particle that left the domain back in, mirroring its position and velocity at
+    /// the edge. `previous` is its position before the last move, which was inside.
+    pub fn reflect(&self, pos: &mut Vec2, vel: &mut Vec2, previous: Vec2, world_size: Vec2) {
+        if self.contains(*pos, world_size) {
+            return;
+        }
+
+        match self {
+            Domain::Rect => {}
+            Domain::Disk { radius } => {
+                reflect_at_circle(pos, vel, previous, world_size / 2., *radius, true)
+            }
+            Domain::Annulus {
+                inner_radius,
+                outer_radius,
+            } => {
+                let center = world_size / 2.;
+                let outside = (*pos - center).length() > *outer_radius;
+                let radius = if outside {
+                    *outer_radius
+                } else {
+                    *inner_radius
+                };
+                reflect_at_circle(pos, vel, previous, center, radius, outside);
+            }
+            Domain::Mask(mask) => {
+                // The edge of a mask has no useful normal, so the velocity is mirrored along
+                // the axes whose movement alone would have left the mask
+                let leaves_x = !mask.contains(Vec2::new(pos.x, previous.y), world_size);
+                let leaves_y = !mask.contains(Vec2::new(previous.x, pos.y), world_size);
+                if leaves_x || !leaves_y {
+                    vel.x = -vel.x;
+                }
+                if leaves_y || !leaves_x {
+                    vel.y = -vel.y;
+                }
+                *pos = previous;
+            }
+        }
+    }
+}
+
+// Mirrors the part of the position and velocity that goes through a circle, `outer` says
+// whether the particle has to stay inside of it or outside of it
+fn reflect_at_circle(
+    pos: &mut Vec2,
+    vel: &mut Vec2,
+    previous: Vec2,
+    center: Vec2,
+    radius: f32,
+    outer: bool,
+) {
+    let offset = *pos - center;
+    let distance = offset.length();
+    if distance == 0. {
+        *pos = previous;
+        *vel = -*vel;
+        return;
+    }
+
+    let normal = offset / distance;
+    let mirrored = (2. * radius - distance).max(0.);
+    // Too fast to end up inside by mirroring, e.g. straight through a thin annulus
+    let mirrored = if outer {
+        mirrored.min(radius)
+    } else {
+        mirrored.max(radius)
+    };
+    *pos = center + normal * mirrored;
+    *vel -= 2. * vel.dot(normal) * normal;
+}
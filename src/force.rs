@@ -0,0 +1,249 @@
+//! Shapes of the force between two particles.
+//!
+//! Every law repels below the `min_distance` of a pair of types, whatever their attraction, and
+//! has no effect from their `max_distance` on. What happens in between is scaled by the
+//! attraction matrix. The kernels
+//! are generic over the law, so every profile gets its own vectorised loops.
+
+use std::f32::consts::PI;
+
+use serde::{Deserialize, Serialize};
+
+use crate::kernel::select;
+use crate::params::SimulationParams;
+
+/// A force curve the kernels can be instantiated with. Implementations should avoid branches
+/// other than simple selects, or the kernels can't be vectorised.
+pub trait ForceLaw: Copy + Send + Sync {
+    /// The law between a pair of types that interact from `min_distance` to `max_distance`,
+    /// see `ParticleType::min_distance`
+    fn new(params: &SimulationParams, min_distance: f32, max_distance: f32) -> Self;
+
+    /// Signed strength of the force towards a particle at `distance`, positive attracts.
+    /// `distance` is below the pair's `max_distance` and may be zero, `attraction` is the
+    /// entry of the attraction matrix for the pair.
+    fn magnitude(&self, distance: f32, attraction: f32) -> f32;
+
+    /// `magnitude` for two attractions at the same distance, e.g. both directions of a pair
+    /// of types that share a law. Laws override it to compute the shape of the curve once.
+    #[inline(always)]
+    fn magnitudes(&self, distance: f32, attraction: f32, other_attraction: f32) -> (f32, f32) {
+        (
+            self.magnitude(distance, attraction),
+            self.magnitude(distance, other_attraction),
+        )
+    }
+}
+
+/// Force laws that can be picked at runtime, see `ForceLaw`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ForceProfile {
+    /// Linear repulsion, then a triangular attraction peak halfway to `max_distance`
+    #[default]
+    Triangle,
+    /// Like the triangle, but every edge is rounded off with a cosine
+    Cosine,
+    /// A steep repulsive core and a short attractive well right outside `min_distance`
+    LennardJones,
+    /// Linear repulsion, then attraction falling off with the square of the distance
+    InversePower,
+}
+
+impl ForceProfile {
+    pub const ALL: [ForceProfile; 4] = [
+        ForceProfile::Triangle,
+        ForceProfile::Cosine,
+        ForceProfile::LennardJones,
+        ForceProfile::InversePower,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ForceProfile::Triangle => "Triangle",
+            ForceProfile::Cosine => "Cosine",
+            ForceProfile::LennardJones => "Lennard-Jones",
+            ForceProfile::InversePower => "Inverse power",
+        }
+    }
+}
+
+// Linear from `-repel_constant` at distance zero to zero at `min_distance`, shared by the laws
+// that only differ in their attraction
+#[inline(always)]
+fn linear_repel(distance: f32, repel_constant: f32, inv_min_distance: f32) -> f32 {
+    -repel_constant * (1. - distance * inv_min_distance)
+}
+
+/// The original force: linear repulsion and a triangular attraction peak
+#[derive(Clone, Copy, Debug)]
+pub struct Triangle {
+    min_distance: f32,
+    mid_distance: f32,
+    inv_min_distance: f32,
+    inv_attract_width: f32,
+    repel_constant: f32,
+    attract_constant: f32,
+}
+
+impl ForceLaw for Triangle {
+    fn new(params: &SimulationParams, min_distance: f32, max_distance: f32) -> Self {
+        Self {
+            min_distance,
+            mid_distance: (max_distance + min_distance) / 2.,
+            inv_min_distance: 1. / min_distance,
+            inv_attract_width: 1. / (max_distance - min_distance),
+            repel_constant: params.repel_constant,
+            attract_constant: params.attract_constant,
+        }
+    }
+
+    #[inline(always)]
+    fn magnitude(&self, distance: f32, attraction: f32) -> f32 {
+        self.magnitudes(distance, attraction, 0.).0
+    }
+
+    #[inline(always)]
+    fn magnitudes(&self, distance: f32, attraction: f32, other_attraction: f32) -> (f32, f32) {
+        let repel = linear_repel(distance, self.repel_constant, self.inv_min_distance);
+        let shape = 1. - (distance - self.mid_distance).abs() * self.inv_attract_width;
+        let attract = self.attract_constant * attraction * shape;
+        let other_attract = self.attract_constant * other_attraction * shape;
+
+        let repels = distance < self.min_distance;
+        (
+            select(repels, repel, attract),
+            select(repels, repel, other_attract),
+        )
+    }
+}
+
+/// Half cosine waves instead of straight lines, so the force has no kinks
+#[derive(Clone, Copy, Debug)]
+pub struct Cosine {
+    min_distance: f32,
+    repel_scale: f32,   // Turns a distance into the phase of the repulsion
+    attract_scale: f32, // Turns a distance past `min_distance` into the phase of the attraction
+    repel_constant: f32,
+    attract_constant: f32,
+}
+
+impl ForceLaw for Cosine {
+    fn new(params: &SimulationParams, min_distance: f32, max_distance: f32) -> Self {
+        Self {
+            min_distance,
+            repel_scale: PI / min_distance,
+            attract_scale: 2. * PI / (max_distance - min_distance),
+            repel_constant: params.repel_constant,
+            attract_constant: params.attract_constant,
+        }
+    }
+
+    #[inline(always)]
+    fn magnitude(&self, distance: f32, attraction: f32) -> f32 {
+        self.magnitudes(distance, attraction, 0.).0
+    }
+
+    #[inline(always)]
+    fn magnitudes(&self, distance: f32, attraction: f32, other_attraction: f32) -> (f32, f32) {
+        let repel = -self.repel_constant * (1. + (distance * self.repel_scale).cos()) / 2.;
+        let phase = (distance - self.min_distance) * self.attract_scale;
+        let shape = 1. - phase.cos();
+        let attract = self.attract_constant * attraction * shape / 2.;
+        let other_attract = self.attract_constant * other_attraction * shape / 2.;
+
+        let repels = distance < self.min_distance;
+        (
+            select(repels, repel, attract),
+            select(repels, repel, other_attract),
+        )
+    }
+}
+
+/// Lennard-Jones force with its minimum energy at `min_distance`. The core is capped at
+/// `repel_constant`, which it reaches at half of `min_distance`, so that overlapping particles
+/// don't fly apart. The well outside peaks at `attract_constant` and falls off with the
+/// sixth power of the distance.
+#[derive(Clone, Copy, Debug)]
+pub struct LennardJones {
+    min_distance: f32,
+    repel_scale: f32,
+    attract_scale: f32,
+}
+
+impl LennardJones {
+    // `s⁶ - s¹²` with `s = min_distance / distance` peaks at a quarter
+    const WELL_DEPTH: f32 = 0.25;
+    // Value of `s¹² - s⁶` at half of `min_distance`
+    const CORE_CAP: f32 = 4096. - 64.;
+}
+
+impl ForceLaw for LennardJones {
+    fn new(params: &SimulationParams, min_distance: f32, _max_distance: f32) -> Self {
+        Self {
+            min_distance,
+            repel_scale: params.repel_constant / Self::CORE_CAP,
+            attract_scale: params.attract_constant / Self::WELL_DEPTH,
+        }
+    }
+
+    #[inline(always)]
+    fn magnitude(&self, distance: f32, attraction: f32) -> f32 {
+        self.magnitudes(distance, attraction, 0.).0
+    }
+
+    #[inline(always)]
+    fn magnitudes(&self, distance: f32, attraction: f32, other_attraction: f32) -> (f32, f32) {
+        let s = self.min_distance / distance.max(self.min_distance / 2.);
+        let s6 = s * s * s * s * s * s;
+        let lj = s6 - s6 * s6;
+        let repel = lj * self.repel_scale;
+        let well = lj * self.attract_scale;
+
+        let repels = distance < self.min_distance;
+        (
+            select(repels, repel, well * attraction),
+            select(repels, repel, well * other_attraction),
+        )
+    }
+}
+
+/// Attraction of `attract_constant` at `min_distance` that falls off with the square of the
+/// distance, like gravity, until it is cut off at `max_distance`
+#[derive(Clone, Copy, Debug)]
+pub struct InversePower {
+    min_distance: f32,
+    inv_min_distance: f32,
+    repel_constant: f32,
+    attract_constant: f32,
+}
+
+impl ForceLaw for InversePower {
+    fn new(params: &SimulationParams, min_distance: f32, _max_distance: f32) -> Self {
+        Self {
+            min_distance,
+            inv_min_distance: 1. / min_distance,
+            repel_constant: params.repel_constant,
+            attract_constant: params.attract_constant,
+        }
+    }
+
+    #[inline(always)]
+    fn magnitude(&self, distance: f32, attraction: f32) -> f32 {
+        self.magnitudes(distance, attraction, 0.).0
+    }
+
+    #[inline(always)]
+    fn magnitudes(&self, distance: f32, attraction: f32, other_attraction: f32) -> (f32, f32) {
+        let repel = linear_repel(distance, self.repel_constant, self.inv_min_distance);
+        let s = self.min_distance / distance.max(self.min_distance);
+        let attract = self.attract_constant * attraction * s * s;
+        let other_attract = self.attract_constant * other_attraction * s * s;
+
+        let repels = distance < self.min_distance;
+        (
+            select(repels, repel, attract),
+            select(repels, repel, other_attract),
+        )
+    }
+}
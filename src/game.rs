@@ -1,13 +1,27 @@
 use macroquad::prelude::*;
+use macroquad::ui::root_ui;
+use particle_life::defaults::type_color;
+use particle_life::domain::Domain;
+use particle_life::particle::{NeighbourSearch, Particles};
+use particle_life::preset::Preset;
+use particle_life::runner::{Rate, Runner};
+use particle_life::{snapshot, Simulation};
+use std::time;
 
-use crate::config::*;
+use crate::cli;
+use crate::config::{Config, ConfigWatcher, SimulationConfig};
 use crate::helpers::ClickType;
-use crate::particle::{Particle, ParticleType, Particles};
+use crate::render::draw_particles;
 use crate::ui::Menu;
 
+// Seconds between checks whether a config file changed
+const CONFIG_POLL_INTERVAL: f64 = 1.;
+
 pub struct Game {
-    particles: Particles,
-    types: Vec<ParticleType>,
+    config: Config,
+    config_watcher: ConfigWatcher,
+    last_config_poll: f64,
+    runner: Runner, // Steps the simulation on its own thread
     menu: Menu,
     camera: Camera2D,
     paused: bool,
@@ -15,104 +29,178 @@ pub struct Game {
 }
 
 impl Game {
-    pub fn new() -> Self {
-        let types = Self::initialize_types(None);
-        let particles = Self::initialize_particles(&types);
-        let menu = Menu::new(&types);
+    pub fn new(config: Config, seed: u64, preset: Option<Preset>) -> Self {
+        let num_types = match &preset {
+            Some(preset) => preset.types.len(),
+            None => config.simulation.types,
+        };
+        let colors: Vec<[f32; 4]> = (0..num_types).map(|i| config.type_color(i)).collect();
+
+        let mut simulation = Simulation::new(
+            Vec2::from(config.simulation.world_size),
+            config.simulation.particles,
+            &colors,
+            config.physics,
+            seed,
+        );
+        simulation.set_threads(config.simulation.threads, config.simulation.pin_threads);
+        apply_particle_settings(&mut simulation.particles, &config.simulation);
+        simulation
+            .particles
+            .set_boundary(config.simulation.boundary);
+        set_domain(&mut simulation, load_domain(&config.simulation));
+        if let Some(preset) = preset {
+            preset.apply(&mut simulation);
+        }
+
+        let window = Vec2::from(config.window.size);
+        let menu = Menu::new(&simulation.types, &config);
+        let runner = Runner::spawn(simulation, rate(&config.simulation));
 
         Game {
-            particles,
-            types,
+            config,
+            config_watcher: ConfigWatcher::new(),
+            last_config_poll: get_time(),
+            runner,
             menu,
-            camera: Camera2D::from_display_rect(Rect::new(
-                0.,
-                0.,
-                WINDOW_SIZE_PX.x,
-                WINDOW_SIZE_PX.y,
-            )),
+            camera: Camera2D::from_display_rect(Rect::new(0., 0., window.x, window.y)),
             paused: false,
             prev_mouse_lclick_pos: None,
         }
     }
 
-    fn initialize_types(value: Option<f32>) -> Vec<ParticleType> {
-        let mut types = Vec::new();
-
-        for color in &COLORS {
-            let mut attract_vec = Vec::new();
-            for _ in 0..COLORS.len() {
-                match value {
-                    Some(value) => attract_vec.push(value),
-                    None => attract_vec.push(rand::gen_range(-1., 1.)),
-                };
-            }
-
-            types.push(ParticleType::new(*color, attract_vec));
-        }
-
-        types
-    }
-
-    fn initialize_particles(types: &Vec<ParticleType>) -> Particles {
-        let mut particles = Particles::new(GAME_AREA_SIZE_U);
-
-        for _ in 0..NUM_PARTICLES {
-            particles.add_particle(Particle::new(
-                [
-                    rand::gen_range(0., GAME_AREA_SIZE_U.x),
-                    rand::gen_range(0., GAME_AREA_SIZE_U.y),
-                ],
-                [0., 0.],
-                rand::gen_range(0, types.len()),
-            ));
-        }
-
-        particles
-    }
-
     pub fn handle_input(&mut self) {
         if is_key_pressed(KeyCode::H) {
             self.menu.toggle_help();
         }
         if is_key_pressed(KeyCode::Space) {
             self.paused = !self.paused;
+            self.runner.set_paused(self.paused);
+        }
+        if is_key_pressed(KeyCode::V) {
+            self.runner.edit(|simulation| {
+                let particles = &mut simulation.particles;
+                let neighbour_search = match particles.neighbour_search() {
+                    NeighbourSearch::Grid => NeighbourSearch::Verlet,
+                    NeighbourSearch::Verlet | NeighbourSearch::BruteForce => NeighbourSearch::Grid,
+                };
+                particles.set_neighbour_search(neighbour_search);
+                println!("\nNeighbour search: {:?}", neighbour_search);
+            });
         }
         if is_key_pressed(KeyCode::P) {
-            self.particles = Self::initialize_particles(&self.types);
+            self.runner.edit(Simulation::randomize_particles);
         }
         if is_key_pressed(KeyCode::A) {
-            self.types = Self::initialize_types(None);
+            self.runner.edit(Simulation::randomize_types);
         }
         if is_key_pressed(KeyCode::C) {
-            self.types = Self::initialize_types(Some(0.));
+            self.runner.edit(Simulation::clear_types);
         }
         if is_key_pressed(KeyCode::N) {
-            self.types = Self::initialize_types(None);
-            self.particles = Self::initialize_particles(&self.types);
+            self.runner.edit(Simulation::randomize_all);
+        }
+        if is_key_pressed(KeyCode::S) {
+            let path = self.config.files.snapshot.clone();
+            let saved = {
+                let path = path.clone();
+                self.runner
+                    .read(move |simulation| snapshot::save(simulation, path))
+            };
+            match saved {
+                Ok(()) => println!("\nSaved snapshot to {}", path.display()),
+                Err(err) => println!("\nCould not save snapshot: {}", err),
+            }
+        }
+        if is_key_pressed(KeyCode::L) {
+            let path = &self.config.files.snapshot;
+            match snapshot::load(path) {
+                Ok(mut simulation) => {
+                    let settings = self.config.simulation.clone();
+                    // The snapshot keeps its own boundary and domain
+                    self.runner.edit(move |current| {
+                        simulation.take_pool(current);
+                        apply_particle_settings(&mut simulation.particles, &settings);
+                        *current = simulation;
+                    });
+                    println!("\nLoaded snapshot from {}", path.display());
+                }
+                Err(err) => println!("\nCould not load snapshot: {}", err),
+            }
+        }
+        if is_key_pressed(KeyCode::E) {
+            let secs = time::SystemTime::now()
+                .duration_since(time::UNIX_EPOCH)
+                .expect("Time travel")
+                .as_secs();
+            let path = self
+                .config
+                .files
+                .presets_dir
+                .join(format!("export-{}.toml", secs));
+            match self.runner.read(Preset::from_simulation).save(&path) {
+                Ok(()) => {
+                    println!("\nExported preset to {}", path.display());
+                    self.menu.refresh_presets();
+                }
+                Err(err) => println!("\nCould not export preset: {}", err),
+            }
+        }
+        if let Some(path) = self.menu.take_selected_preset() {
+            match Preset::load(&path) {
+                Ok(preset) => self.runner.edit(move |simulation| preset.apply(simulation)),
+                Err(err) => println!("\nCould not load {}: {}", path.display(), err),
+            }
+        }
+        if is_key_pressed(KeyCode::Equal) || is_key_pressed(KeyCode::KpAdd) {
+            // The color is picked on the simulation thread, where the number of types is current
+            let palette = self.config.simulation.palette.clone();
+            self.runner.edit(move |simulation| {
+                simulation.add_type(type_color(&palette, simulation.types.len()));
+            });
+        }
+        if is_key_pressed(KeyCode::Minus) || is_key_pressed(KeyCode::KpSubtract) {
+            self.runner.edit(|simulation| {
+                simulation.remove_type(simulation.types.len() - 1);
+            });
         }
 
         let mouse_pos = mouse_position();
         let mouse_pos = Vec2::new(mouse_pos.0, mouse_pos.1);
-        if is_mouse_button_down(MouseButton::Left) && !self.menu.area.contains(mouse_pos) {
+        // Windows outside of the menu, like the type window, keep their clicks
+        let over_window = root_ui().is_mouse_over(mouse_pos);
+        if is_mouse_button_down(MouseButton::Left)
+            && !self.menu.area.contains(mouse_pos)
+            && !over_window
+        {
             match self.prev_mouse_lclick_pos {
                 None => {
                     self.prev_mouse_lclick_pos = Some(mouse_pos);
                 }
                 Some(prev_pos) => {
                     self.camera.target -= Vec2::new(1., -1.) * (mouse_pos - prev_pos)
-                        / (self.camera.zoom * CAMERA_DRAG_SPEED);
+                        / (self.camera.zoom * self.config.camera.drag_speed);
 
                     self.prev_mouse_lclick_pos = Some(mouse_pos);
                 }
             }
         }
 
-        if is_mouse_button_down(MouseButton::Left) && self.menu.area.contains(mouse_pos) {
-            self.menu.click(mouse_pos, &mut self.types, ClickType::Left);
-        }
-        if is_mouse_button_down(MouseButton::Right) && self.menu.area.contains(mouse_pos) {
-            self.menu
-                .click(mouse_pos, &mut self.types, ClickType::Right);
+        let click_type = if is_mouse_button_down(MouseButton::Left) {
+            Some(ClickType::Left)
+        } else if is_mouse_button_down(MouseButton::Right) {
+            Some(ClickType::Right)
+        } else {
+            None
+        };
+        if let Some(click_type) = click_type.filter(|_| self.menu.area.contains(mouse_pos)) {
+            if let Some(edit) = self.menu.click(mouse_pos, click_type) {
+                self.runner
+                    .edit(move |simulation| edit.apply(&mut simulation.types));
+            }
+            if is_mouse_button_pressed(MouseButton::Left) {
+                self.menu.select_type(mouse_pos);
+            }
         }
 
         if is_mouse_button_released(MouseButton::Left) {
@@ -129,7 +217,7 @@ impl Game {
                     0.0
                 };
 
-                let factor = (1. + CAMERA_ZOOM_SPEED).powf(y);
+                let factor = (1. + self.config.camera.zoom_speed).powf(y);
                 self.camera.zoom *= factor;
 
                 // Zoom in to cursor position
@@ -148,21 +236,137 @@ impl Game {
         }
     }
 
-    pub fn update(&mut self) {
-        if !self.paused {
-            self.particles.update(&self.types);
+    fn reload_config(&mut self) {
+        if get_time() - self.last_config_poll < CONFIG_POLL_INTERVAL {
+            return;
+        }
+        self.last_config_poll = get_time();
+
+        let mut config = match self.config_watcher.poll() {
+            None => return,
+            Some(Ok(config)) => config,
+            Some(Err(err)) => {
+                println!("\nKeeping the previous config, {}", err);
+                return;
+            }
+        };
+        cli::args().apply(&mut config);
+        if let Err(err) = config.validate() {
+            println!("\nKeeping the previous config, {}", err);
+            return;
         }
+
+        // Only overwrite what changed, so slider tweaks survive unrelated edits
+        let params = (config.physics != self.config.physics).then_some(config.physics);
+        let boundary = (config.simulation.boundary != self.config.simulation.boundary)
+            .then_some(config.simulation.boundary);
+        let domain = (config.simulation.domain != self.config.simulation.domain)
+            .then(|| load_domain(&config.simulation));
+        let settings = config.simulation.clone();
+        self.runner.edit(move |simulation| {
+            if let Some(params) = params {
+                simulation.params = params;
+            }
+            simulation.set_threads(settings.threads, settings.pin_threads);
+            apply_particle_settings(&mut simulation.particles, &settings);
+            if let Some(boundary) = boundary {
+                simulation.particles.set_boundary(boundary);
+            }
+            if let Some(domain) = domain {
+                set_domain(simulation, domain);
+            }
+        });
+        self.runner.set_rate(rate(&config.simulation));
+        self.menu.set_config(&config);
+        self.config = config;
+        println!("\nReloaded config");
+        if let Some(err) = self.threads_error() {
+            println!("Could not start worker threads, running serially: {}", err);
+        }
+    }
+
+    /// Why the worker threads couldn't be started, if they couldn't
+    pub fn threads_error(&self) -> Option<String> {
+        self.runner
+            .read(|simulation| simulation.threads_error().map(str::to_owned))
+    }
+
+    /// Force pass time of every worker thread in the last step, e.g. "4.1 3.9 4.3 ms"
+    pub fn thread_times(&mut self) -> String {
+        let times: Vec<String> = self
+            .runner
+            .frame()
+            .busy_times
+            .iter()
+            .map(|time| format!("{:.1}", time.as_secs_f64() * 1000.))
+            .collect();
+        format!("{} ms", times.join(" "))
+    }
+
+    /// Steps the simulation thread managed in the last second
+    pub fn steps_per_second(&mut self) -> f32 {
+        self.runner.frame().steps_per_second
+    }
+
+    pub fn update(&mut self) {
+        self.reload_config();
     }
 
     pub fn draw(&mut self) {
         clear_background(BLACK);
 
+        // Newest frame the simulation thread published, it keeps stepping while this draws
+        let frame = self.runner.frame();
+        self.menu.sync_types(&frame.types);
+
         set_camera(&self.camera); // For drawing particles with the new zoom
-        self.particles.draw(&self.types, &self.camera);
+        draw_particles(frame, &self.camera);
 
         set_default_camera(); // For drawing the menu
-        self.menu.draw(&self.types);
+        let mut params = frame.params;
+        self.menu.draw(frame, &mut params);
+        if params != frame.params {
+            self.runner
+                .edit(move |simulation| simulation.params = params);
+        }
+        if let Some(edit) = self.menu.take_type_edit() {
+            self.runner
+                .edit(move |simulation| edit.apply(&mut simulation.types));
+        }
 
         set_camera(&self.camera);
     }
 }
+
+// The domain was checked when the config was loaded, but a mask could have changed since
+fn load_domain(config: &SimulationConfig) -> Domain {
+    Domain::load(&config.domain).unwrap_or_else(|err| {
+        println!("\nIgnoring simulation.domain, {}", err);
+        Domain::Rect
+    })
+}
+
+// The world can be smaller than the one the domain was checked for, e.g. after loading a
+// snapshot, so the domain may still not fit
+fn set_domain(simulation: &mut Simulation, domain: Domain) {
+    if let Err(err) = simulation.set_domain(domain) {
+        println!("\nIgnoring simulation.domain, {}", err);
+    }
+}
+
+// Settings that only change how particles are updated, not the particles themselves
+fn apply_particle_settings(particles: &mut Particles, config: &SimulationConfig) {
+    particles.set_half_stencil(config.half_stencil);
+    particles.set_neighbour_search(config.neighbour_search);
+    particles.set_verlet_skin(config.verlet_skin);
+    if particles.reorder_interval() != config.reorder_interval {
+        particles.set_reorder_interval(config.reorder_interval);
+    }
+}
+
+fn rate(config: &SimulationConfig) -> Rate {
+    Rate {
+        steps_per_second: config.steps_per_second,
+        substeps: config.substeps,
+    }
+}
@@ -1,75 +1,306 @@
-use macroquad::prelude::*;
+//! Uniform grid over the world, used to only compare particles in neighbouring cells.
+//!
+//! Cells are at least as large as the interaction range along both axes and fill the world
+//! exactly, so they are generally not square. Worlds less than three cells across wrap
+//! around onto the same cells, every stencil visits each of them only once. Along axes that
+//! aren't periodic the stencil stops at the edge instead of wrapping around.
+//!
+//! The grid is stored flat: the particles of cell `c` are
+//! `particle_index[cell_start[c]..cell_start[c + 1]]`, in ascending order. It is refilled
+//! with a counting sort every update and keeps its buffers between updates.
 
-#[derive(Debug)]
-pub struct Cell {
-    pub pos: (usize, usize),
-    rect: Rect,
-    pub particles: Vec<usize>,
-}
+use glam::Vec2;
+use std::ops::Range;
+
+use crate::pool::WorkerPool;
 
 pub struct Grid {
-    pub cells: Vec<Cell>,
     pub shape: (usize, usize),
-    cell_size: f32,
+    cell_size: Vec2,
+    periodic: [bool; 2],        // Whether the x and y axes wrap around
+    cell_start: Vec<usize>,     // One entry per cell plus a final one holding the particle count
+    particle_index: Vec<usize>, // Particle indices sorted by cell
+    cell_of: Vec<usize>,        // Cell of every particle
+    morton_cells: Vec<usize>,   // Every cell, ordered along a Z-order curve
+    partial: Vec<PartialSort>,  // One per worker
+}
+
+// Counting sort of a contiguous range of particles, merged into the grid afterwards
+#[derive(Default)]
+struct PartialSort {
+    cell_start: Vec<usize>,
+    particle_index: Vec<usize>,
 }
 
 impl Grid {
     pub fn new(game_area_size: Vec2, max_effect_range: f32) -> Self {
-        let mut cell_size = max_effect_range;
-        let shape = (
-            (game_area_size.x / cell_size).floor() as usize,
-            (game_area_size.y / cell_size).floor() as usize,
-        );
-        cell_size += (game_area_size.x % cell_size) / shape.0 as f32;
-        let mut cells = Vec::new();
-        for y in 0..shape.1 {
-            for x in 0..shape.0 {
-                cells.push(Cell {
-                    pos: (x, y),
-                    rect: Rect::new(
-                        x as f32 * cell_size,
-                        y as f32 * cell_size,
-                        cell_size,
-                        cell_size,
-                    ),
-                    particles: Vec::new(),
-                });
+        let mut grid = Self {
+            shape: (0, 0),
+            cell_size: Vec2::ZERO,
+            periodic: [true; 2],
+            cell_start: Vec::new(),
+            particle_index: Vec::new(),
+            cell_of: Vec::new(),
+            morton_cells: Vec::new(),
+            partial: Vec::new(),
+        };
+        grid.resize(game_area_size, max_effect_range);
+
+        grid
+    }
+
+    /// Recomputes the cell layout, e.g. after the interaction range changed.
+    /// The grid is empty until the next `rebuild`.
+    pub fn resize(&mut self, game_area_size: Vec2, max_effect_range: f32) {
+        let cells = |length: f32| ((length / max_effect_range).floor() as usize).max(1);
+        let shape = (cells(game_area_size.x), cells(game_area_size.y));
+
+        self.shape = shape;
+        self.cell_size = game_area_size / Vec2::new(shape.0 as f32, shape.1 as f32);
+        self.cell_start.clear();
+        self.cell_start.resize(self.num_cells() + 1, 0);
+        self.particle_index.clear();
+
+        self.morton_cells.clear();
+        self.morton_cells.extend(0..self.num_cells());
+        self.morton_cells
+            .sort_by_key(|&cell| morton_code(cell % shape.0, cell / shape.0));
+    }
+
+    pub fn cell_size(&self) -> Vec2 {
+        self.cell_size
+    }
+
+    pub fn periodic(&self) -> [bool; 2] {
+        self.periodic
+    }
+
+    /// Sets which axes wrap around, see `Boundary::periodic`
+    pub fn set_periodic(&mut self, periodic: [bool; 2]) {
+        self.periodic = periodic;
+    }
+
+    pub fn num_cells(&self) -> usize {
+        self.shape.0 * self.shape.1
+    }
+
+    /// Positions on the far edge of an axis that isn't periodic belong to the last cell
+    pub fn cell_pos_from_pos(&self, pos: Vec2) -> (usize, usize) {
+        let wrap = |cell: usize, len: usize, periodic: bool| {
+            if periodic {
+                cell % len
+            } else {
+                cell.min(len - 1)
             }
-        }
+        };
+        let x = (pos.x / self.cell_size.x).floor() as usize;
+        let y = (pos.y / self.cell_size.y).floor() as usize;
+
+        (
+            wrap(x, self.shape.0, self.periodic[0]),
+            wrap(y, self.shape.1, self.periodic[1]),
+        )
+    }
+
+    pub fn cell_index(&self, x: usize, y: usize) -> usize {
+        y * self.shape.0 + x
+    }
+
+    pub fn cell_pos(&self, cell: usize) -> (usize, usize) {
+        (cell % self.shape.0, cell / self.shape.0)
+    }
+
+    /// The 3x3 block of cells around `cell`, wrapping around the edges of periodic axes and
+    /// cut off at the others. Each cell is only returned once, even if the block wraps onto
+    /// it more than once.
+    pub fn neighbour_cells(&self, cell: usize) -> impl Iterator<Item = usize> + '_ {
+        let (cell_x, cell_y) = self.cell_pos(cell);
+        let [periodic_x, periodic_y] = self.periodic;
+        wrapped_neighbours(cell_y, self.shape.1, periodic_y).flat_map(move |y| {
+            wrapped_neighbours(cell_x, self.shape.0, periodic_x).map(move |x| self.cell_index(x, y))
+        })
+    }
 
-        Self {
-            cells,
-            shape,
-            cell_size,
+    /// Sorted index ranges covering the 3x3 block of cells around `cell`, wrapping around the
+    /// periodic edges of the game area. Neighbouring cells of a row are next to each other in
+    /// sorted order, so away from the edges every row is a single range and the rest stay empty.
+    /// Cells the block wraps onto more than once are only covered once.
+    pub fn neighbour_ranges(&self, cell: usize) -> [Range<usize>; 9] {
+        let (cell_x, cell_y) = self.cell_pos(cell);
+        let (width, height) = self.shape;
+        let [periodic_x, periodic_y] = self.periodic;
+        let cell_start = self.cell_start();
+
+        let mut ranges: [Range<usize>; 9] = Default::default();
+        let mut k = 0;
+        for y in wrapped_neighbours(cell_y, height, periodic_y) {
+            if cell_x > 0 && cell_x + 1 < width {
+                let first = self.cell_index(cell_x - 1, y);
+                ranges[k] = cell_start[first]..cell_start[first + 3];
+                k += 1;
+            } else {
+                for x in wrapped_neighbours(cell_x, width, periodic_x) {
+                    let cell2 = self.cell_index(x, y);
+                    ranges[k] = cell_start[cell2]..cell_start[cell2 + 1];
+                    k += 1;
+                }
+            }
         }
+
+        ranges
     }
 
-    pub fn cell_pos_from_pos(&self, pos: Vec2) -> (usize, usize) {
-        let mut x = (pos.x / self.cell_size).floor() as usize;
-        x %= self.shape.0;
+    /// Indices of the particles in `cell`, in ascending order
+    pub fn cell(&self, cell: usize) -> &[usize] {
+        &self.particle_index[self.cell_start[cell]..self.cell_start[cell + 1]]
+    }
+
+    /// Every cell, ordered so that cells close to each other in space are mostly close in
+    /// the order as well
+    pub fn morton_cells(&self) -> &[usize] {
+        &self.morton_cells
+    }
 
-        let mut y = (pos.y / self.cell_size).floor() as usize;
-        y %= self.shape.1;
+    pub fn cell_start(&self) -> &[usize] {
+        &self.cell_start
+    }
 
-        (x, y)
+    pub fn particle_index(&self) -> &[usize] {
+        &self.particle_index
     }
 
-    pub fn insert(&mut self, index: usize, pos: Vec2) {
-        let (x, y) = self.cell_pos_from_pos(pos);
-        self.cells[y * self.shape.0 + x].particles.push(index);
+    /// Sorts `num_particles` particles into their cells. `pos` gives the position of a particle.
+    ///
+    /// Every worker counting-sorts its own range of particles, then the partial results are
+    /// merged cell range by cell range. Particles end up in ascending order within their cell
+    /// no matter how many workers there are.
+    pub fn rebuild(
+        &mut self,
+        num_particles: usize,
+        pos: impl Fn(usize) -> Vec2 + Sync,
+        pool: &WorkerPool,
+    ) {
+        let num_cells = self.num_cells();
+        let tasks = pool.threads();
+        let per_task = num_particles.div_ceil(tasks).max(1);
+
+        let mut cell_of = std::mem::take(&mut self.cell_of);
+        cell_of.resize(num_particles, 0);
+        let this = &*self;
+        pool.for_each(
+            cell_of.chunks_mut(per_task).enumerate(),
+            |(chunk, cells)| {
+                for (k, cell) in cells.iter_mut().enumerate() {
+                    let (x, y) = this.cell_pos_from_pos(pos(chunk * per_task + k));
+                    *cell = this.cell_index(x, y);
+                }
+            },
+        );
+        self.cell_of = cell_of;
+
+        self.partial.resize_with(tasks, PartialSort::default);
+        let cell_of = &self.cell_of;
+        pool.for_each(self.partial.iter_mut().enumerate(), |(task, partial)| {
+            let first = (task * per_task).min(num_particles);
+            let last = (first + per_task).min(num_particles);
+            partial.sort(first, &cell_of[first..last], num_cells);
+        });
+
+        // A cell starts after the particles of all previous cells of every partial sort
+        self.cell_start.clear();
+        self.cell_start.resize(num_cells + 1, 0);
+        for partial in &self.partial {
+            for (start, partial_start) in self.cell_start.iter_mut().zip(&partial.cell_start) {
+                *start += partial_start;
+            }
+        }
+
+        self.particle_index.resize(num_particles, 0);
+        let cells_per_task = num_cells.div_ceil(tasks).max(1);
+        let cell_start = &self.cell_start;
+        let partial = &self.partial;
+        let mut rest = self.particle_index.as_mut_slice();
+        let mut first_cell = 0;
+        let ranges = std::iter::from_fn(|| {
+            if first_cell >= num_cells {
+                return None;
+            }
+            let cells = first_cell..(first_cell + cells_per_task).min(num_cells);
+            let len = cell_start[cells.end] - cell_start[cells.start];
+            let (out, tail) = std::mem::take(&mut rest).split_at_mut(len);
+            rest = tail;
+            first_cell = cells.end;
+            Some((cells, out))
+        });
+        pool.for_each(ranges, |(cells, out)| {
+            let mut k = 0;
+            for cell in cells {
+                for partial in partial {
+                    let indices = partial.cell(cell);
+                    out[k..k + indices.len()].copy_from_slice(indices);
+                    k += indices.len();
+                }
+            }
+        });
     }
+}
+
+impl PartialSort {
+    // Counting sort of the particles starting at `first`, whose cells are `cell_of`
+    fn sort(&mut self, first: usize, cell_of: &[usize], num_cells: usize) {
+        self.cell_start.clear();
+        self.cell_start.resize(num_cells + 1, 0);
+        for &cell in cell_of {
+            self.cell_start[cell + 1] += 1;
+        }
+        for cell in 0..num_cells {
+            self.cell_start[cell + 1] += self.cell_start[cell];
+        }
 
-    #[allow(unused)]
-    pub fn draw(&self) {
-        for cell in &self.cells {
-            draw_rectangle_lines(
-                cell.rect.x,
-                cell.rect.y,
-                cell.rect.w,
-                cell.rect.h,
-                5.,
-                WHITE,
-            );
+        // Advances every start to the end of its cell, which is the start of the next one
+        self.particle_index.resize(cell_of.len(), 0);
+        for (k, &cell) in cell_of.iter().enumerate() {
+            self.particle_index[self.cell_start[cell]] = first + k;
+            self.cell_start[cell] += 1;
         }
+        self.cell_start.copy_within(0..num_cells, 1);
+        self.cell_start[0] = 0;
+    }
+
+    fn cell(&self, cell: usize) -> &[usize] {
+        &self.particle_index[self.cell_start[cell]..self.cell_start[cell + 1]]
     }
 }
+
+/// The positions before `pos`, `pos` itself and after it on an axis of `len` cells, wrapping
+/// around the ends if the axis is periodic. Fewer than three cells are each returned once.
+pub fn wrapped_neighbours(pos: usize, len: usize, periodic: bool) -> impl Iterator<Item = usize> {
+    let wraps = periodic && len > 2;
+    let before = match pos {
+        0 if wraps => Some(len - 1),
+        0 => None,
+        _ => Some(pos - 1),
+    };
+    let after = match pos + 1 {
+        next if next < len => Some(next),
+        _ if wraps => Some(0),
+        _ => None,
+    };
+
+    before.into_iter().chain(Some(pos)).chain(after)
+}
+
+// Interleaves the bits of the cell coordinates, giving the position along a Z-order curve
+fn morton_code(x: usize, y: usize) -> u64 {
+    fn spread(v: usize) -> u64 {
+        let mut v = v as u64 & 0xffff_ffff;
+        v = (v | (v << 16)) & 0x0000_ffff_0000_ffff;
+        v = (v | (v << 8)) & 0x00ff_00ff_00ff_00ff;
+        v = (v | (v << 4)) & 0x0f0f_0f0f_0f0f_0f0f;
+        v = (v | (v << 2)) & 0x3333_3333_3333_3333;
+        v = (v | (v << 1)) & 0x5555_5555_5555_5555;
+        v
+    }
+
+    spread(x) | (spread(y) << 1)
+}
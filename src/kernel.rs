@@ -0,0 +1,349 @@
+//! Vectorised force loops over particles stored in grid order.
+//!
+//! Neighbours are processed in blocks of `LANES` fixed-size arrays with every branch written
+//! as a select, so that the compiler turns each block into SIMD instructions.
+
+use glam::Vec2;
+
+use crate::force::ForceLaw;
+use crate::params::SimulationParams;
+use crate::particle::ParticleType;
+
+const LANES: usize = 8;
+
+// How a particle is affected by one type of neighbour
+#[derive(Clone, Copy)]
+pub(crate) struct Pair<F> {
+    pub law: F,
+    pub attraction: f32,
+    pub max_distance: f32,
+}
+
+// Everything the kernels need besides the particles, derived from the params and types once
+// per update
+pub(crate) struct KernelConsts<F> {
+    pub wrap_size: Vec2, // See `Boundary::wrap_size`
+    pub half_wrap_size: Vec2,
+    pub range: f32, // Largest distance at which any pair of types interacts
+    // Whether every pair of types has the same radii both ways, and so the same law
+    pub symmetric_radii: bool,
+    num_types: usize,
+    pairs: Vec<Pair<F>>, // Row `t` holds how type `t` is affected by every type
+    pairs_from: Vec<Pair<F>>, // Row `t` holds how every type is affected by type `t`
+}
+
+impl<F: ForceLaw> KernelConsts<F> {
+    pub fn new(wrap_size: Vec2, params: &SimulationParams, types: &[ParticleType]) -> Self {
+        let num_types = types.len();
+        let pair = |type1: &ParticleType, type2: usize| {
+            let max_distance = type1.max_distance[type2];
+            Pair {
+                law: F::new(params, type1.min_distance[type2], max_distance),
+                attraction: type1.attraction[type2],
+                max_distance,
+            }
+        };
+
+        Self {
+            wrap_size,
+            half_wrap_size: wrap_size / 2.,
+            range: types.iter().map(ParticleType::range).fold(0., f32::max),
+            symmetric_radii: types.iter().enumerate().all(|(t1, type1)| {
+                types.iter().enumerate().all(|(t2, type2)| {
+                    type1.min_distance[t2] == type2.min_distance[t1]
+                        && type1.max_distance[t2] == type2.max_distance[t1]
+                })
+            }),
+            num_types,
+            pairs: types
+                .iter()
+                .flat_map(|type1| (0..num_types).map(move |type2| pair(type1, type2)))
+                .collect(),
+            pairs_from: (0..num_types)
+                .flat_map(|type2| types.iter().map(move |type1| pair(type1, type2)))
+                .collect(),
+        }
+    }
+
+    pub fn pairs(&self, type_id: usize) -> &[Pair<F>] {
+        &self.pairs[type_id * self.num_types..(type_id + 1) * self.num_types]
+    }
+
+    pub fn pairs_from(&self, type_id: usize) -> &[Pair<F>] {
+        &self.pairs_from[type_id * self.num_types..(type_id + 1) * self.num_types]
+    }
+}
+
+/// Force on a particle at `pos` from the particles at `xs`/`ys` with types `type_ids`.
+/// The particle itself may be among them, a distance of zero contributes no force.
+pub(crate) fn simd_force<F: ForceLaw>(
+    pos: Vec2,
+    xs: &[f32],
+    ys: &[f32],
+    type_ids: &[u8],
+    pairs: &[Pair<F>],
+    consts: &KernelConsts<F>,
+) -> Vec2 {
+    let mut fx = [0.; LANES];
+    let mut fy = [0.; LANES];
+
+    let blocks = xs
+        .chunks_exact(LANES)
+        .zip(ys.chunks_exact(LANES))
+        .zip(type_ids.chunks_exact(LANES));
+    for ((bx, by), bt) in blocks {
+        let dx = std::array::from_fn(|l| bx[l] - pos.x);
+        let dy = std::array::from_fn(|l| by[l] - pos.y);
+        let p = std::array::from_fn(|l| pairs[bt[l] as usize]);
+        simd_block(&mut fx, &mut fy, dx, dy, p, consts);
+    }
+
+    // The rest is padded with lanes at distance zero
+    let rest = xs.len() % LANES;
+    if rest > 0 {
+        let first = xs.len() - rest;
+        let mut dx = [0.; LANES];
+        let mut dy = [0.; LANES];
+        let mut p = [pairs[0]; LANES];
+        for l in 0..rest {
+            dx[l] = xs[first + l] - pos.x;
+            dy[l] = ys[first + l] - pos.y;
+            p[l] = pairs[type_ids[first + l] as usize];
+        }
+        simd_block(&mut fx, &mut fy, dx, dy, p, consts);
+    }
+
+    Vec2::new(fx.iter().sum(), fy.iter().sum())
+}
+
+/// Force on a particle at `pos` from the particles at the indices `neighbours`
+pub(crate) fn simd_list_force<F: ForceLaw>(
+    pos: Vec2,
+    xs: &[f32],
+    ys: &[f32],
+    type_ids: &[u8],
+    neighbours: &[u32],
+    pairs: &[Pair<F>],
+    consts: &KernelConsts<F>,
+) -> Vec2 {
+    let mut fx = [0.; LANES];
+    let mut fy = [0.; LANES];
+
+    let mut blocks = neighbours.chunks_exact(LANES);
+    for block in &mut blocks {
+        let dx = std::array::from_fn(|l| xs[block[l] as usize] - pos.x);
+        let dy = std::array::from_fn(|l| ys[block[l] as usize] - pos.y);
+        let p = std::array::from_fn(|l| pairs[type_ids[block[l] as usize] as usize]);
+        simd_block(&mut fx, &mut fy, dx, dy, p, consts);
+    }
+
+    // The rest is padded with lanes at distance zero
+    let rest = blocks.remainder();
+    if !rest.is_empty() {
+        let mut dx = [0.; LANES];
+        let mut dy = [0.; LANES];
+        let mut p = [pairs[0]; LANES];
+        for (l, &j) in rest.iter().enumerate() {
+            dx[l] = xs[j as usize] - pos.x;
+            dy[l] = ys[j as usize] - pos.y;
+            p[l] = pairs[type_ids[j as usize] as usize];
+        }
+        simd_block(&mut fx, &mut fy, dx, dy, p, consts);
+    }
+
+    Vec2::new(fx.iter().sum(), fy.iter().sum())
+}
+
+// Same force as `Particles::scalar_force` for one block of neighbours
+#[inline(always)]
+fn simd_block<F: ForceLaw>(
+    fx: &mut [f32; LANES],
+    fy: &mut [f32; LANES],
+    mut dx: [f32; LANES],
+    mut dy: [f32; LANES],
+    pairs: [Pair<F>; LANES],
+    consts: &KernelConsts<F>,
+) {
+    for l in 0..LANES {
+        let (distance, inv_distance) = wrap_offset(&mut dx[l], &mut dy[l], consts);
+        let force = pair_force(distance, &pairs[l]) * inv_distance;
+
+        fx[l] += force * dx[l];
+        fy[l] += force * dy[l];
+    }
+}
+
+/// Forces between a particle at `pos` and the particles at `xs`/`ys`, computing every
+/// distance once. The force on the particle is returned, the forces on the others are added
+/// to `other_forces`.
+///
+/// `pairs_to` holds how the particle is affected by every type, `pairs_from` how every type is
+/// affected by the particle. The particle itself must not be among the others.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn simd_pair_forces<F: ForceLaw>(
+    pos: Vec2,
+    xs: &[f32],
+    ys: &[f32],
+    type_ids: &[u8],
+    pairs_to: &[Pair<F>],
+    pairs_from: &[Pair<F>],
+    consts: &KernelConsts<F>,
+    other_forces: &mut [Vec2],
+) -> Vec2 {
+    // With symmetric radii both directions of a pair share their law, which is then only
+    // evaluated once
+    if consts.symmetric_radii {
+        pair_forces::<F, true>(
+            pos,
+            xs,
+            ys,
+            type_ids,
+            pairs_to,
+            pairs_from,
+            consts,
+            other_forces,
+        )
+    } else {
+        pair_forces::<F, false>(
+            pos,
+            xs,
+            ys,
+            type_ids,
+            pairs_to,
+            pairs_from,
+            consts,
+            other_forces,
+        )
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+#[inline(always)]
+fn pair_forces<F: ForceLaw, const SAME_LAW: bool>(
+    pos: Vec2,
+    xs: &[f32],
+    ys: &[f32],
+    type_ids: &[u8],
+    pairs_to: &[Pair<F>],
+    pairs_from: &[Pair<F>],
+    consts: &KernelConsts<F>,
+    other_forces: &mut [Vec2],
+) -> Vec2 {
+    let mut fx = [0.; LANES];
+    let mut fy = [0.; LANES];
+
+    let blocks = xs
+        .chunks_exact(LANES)
+        .zip(ys.chunks_exact(LANES))
+        .zip(type_ids.chunks_exact(LANES))
+        .zip(other_forces.chunks_exact_mut(LANES));
+    for (((bx, by), bt), bf) in blocks {
+        let dx = std::array::from_fn(|l| bx[l] - pos.x);
+        let dy = std::array::from_fn(|l| by[l] - pos.y);
+        let to = std::array::from_fn(|l| pairs_to[bt[l] as usize]);
+        let from = std::array::from_fn(|l| pairs_from[bt[l] as usize]);
+        let (other_fx, other_fy) =
+            simd_pair_block::<F, SAME_LAW>(&mut fx, &mut fy, dx, dy, to, from, consts);
+        for l in 0..LANES {
+            bf[l] += Vec2::new(other_fx[l], other_fy[l]);
+        }
+    }
+
+    // The rest is padded with lanes at distance zero
+    let rest = xs.len() % LANES;
+    if rest > 0 {
+        let first = xs.len() - rest;
+        let mut dx = [0.; LANES];
+        let mut dy = [0.; LANES];
+        let mut to = [pairs_to[0]; LANES];
+        let mut from = [pairs_from[0]; LANES];
+        for l in 0..rest {
+            dx[l] = xs[first + l] - pos.x;
+            dy[l] = ys[first + l] - pos.y;
+            to[l] = pairs_to[type_ids[first + l] as usize];
+            from[l] = pairs_from[type_ids[first + l] as usize];
+        }
+        let (other_fx, other_fy) =
+            simd_pair_block::<F, SAME_LAW>(&mut fx, &mut fy, dx, dy, to, from, consts);
+        for l in 0..rest {
+            other_forces[first + l] += Vec2::new(other_fx[l], other_fy[l]);
+        }
+    }
+
+    Vec2::new(fx.iter().sum(), fy.iter().sum())
+}
+
+// One block of `simd_pair_forces`, returns the forces on the others. With `SAME_LAW` only the
+// attraction of `pairs_from` is used.
+#[inline(always)]
+fn simd_pair_block<F: ForceLaw, const SAME_LAW: bool>(
+    fx: &mut [f32; LANES],
+    fy: &mut [f32; LANES],
+    mut dx: [f32; LANES],
+    mut dy: [f32; LANES],
+    pairs_to: [Pair<F>; LANES],
+    pairs_from: [Pair<F>; LANES],
+    consts: &KernelConsts<F>,
+) -> ([f32; LANES], [f32; LANES]) {
+    let mut other_fx = [0.; LANES];
+    let mut other_fy = [0.; LANES];
+    for l in 0..LANES {
+        let (distance, inv_distance) = wrap_offset(&mut dx[l], &mut dy[l], consts);
+        let (force, other_force) = if SAME_LAW {
+            let (to, from) = (&pairs_to[l], &pairs_from[l]);
+            let (force, other_force) = to.law.magnitudes(distance, to.attraction, from.attraction);
+            let in_range = distance < to.max_distance;
+            (
+                select(in_range, force, 0.),
+                select(in_range, other_force, 0.),
+            )
+        } else {
+            (
+                pair_force(distance, &pairs_to[l]),
+                pair_force(distance, &pairs_from[l]),
+            )
+        };
+        let force = force * inv_distance;
+        let other_force = other_force * inv_distance;
+
+        fx[l] += force * dx[l];
+        fy[l] += force * dy[l];
+        // The other particle sees the offset the other way around
+        other_fx[l] = -other_force * dx[l];
+        other_fy[l] = -other_force * dy[l];
+    }
+
+    (other_fx, other_fy)
+}
+
+// Takes the shortest way around the periodic edges, returns the distance and its inverse
+// (zero for a distance of zero)
+#[inline(always)]
+fn wrap_offset<F>(dx: &mut f32, dy: &mut f32, consts: &KernelConsts<F>) -> (f32, f32) {
+    *dx -= select(*dx > consts.half_wrap_size.x, consts.wrap_size.x, 0.);
+    *dx += select(*dx < -consts.half_wrap_size.x, consts.wrap_size.x, 0.);
+    *dy -= select(*dy > consts.half_wrap_size.y, consts.wrap_size.y, 0.);
+    *dy += select(*dy < -consts.half_wrap_size.y, consts.wrap_size.y, 0.);
+
+    let distance = (*dx * *dx + *dy * *dy).sqrt();
+    let inv_distance = select(distance > 0., 1. / distance, 0.);
+
+    (distance, inv_distance)
+}
+
+// Signed strength of the force towards a particle at `distance`, see `ForceLaw::magnitude`
+#[inline(always)]
+fn pair_force<F: ForceLaw>(distance: f32, pair: &Pair<F>) -> f32 {
+    let force = pair.law.magnitude(distance, pair.attraction);
+
+    select(distance < pair.max_distance, force, 0.)
+}
+
+#[inline(always)]
+pub(crate) fn select(condition: bool, a: f32, b: f32) -> f32 {
+    if condition {
+        a
+    } else {
+        b
+    }
+}
@@ -0,0 +1,25 @@
+//! Headless particle life simulation.
+//!
+//! Everything needed to create and step a simulation lives here and has no
+//! dependency on a window or graphics context. The macroquad app in `main.rs`
+//! is one frontend on top of it.
+
+pub mod boundary;
+pub mod defaults;
+pub mod domain;
+pub mod force;
+pub mod grid;
+mod kernel;
+pub mod params;
+pub mod particle;
+pub mod pool;
+pub mod preset;
+pub mod runner;
+pub mod simulation;
+pub mod snapshot;
+pub mod verlet;
+
+pub use force::ForceProfile;
+pub use glam::Vec2;
+pub use params::{Integrator, SimulationParams};
+pub use simulation::Simulation;
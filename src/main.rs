@@ -1,23 +1,45 @@
 use macroquad::prelude::*;
+use particle_life::preset::Preset;
 use std::io;
 use std::io::Write;
+use std::process;
+use std::sync::OnceLock;
 use std::time;
 
+mod cli;
 mod config;
 mod game;
-mod grid;
 mod helpers;
-mod particle;
+mod render;
 mod ui;
 
-use config::*;
+use config::Config;
 use game::*;
 
+// Config files with the command line options applied on top, loaded before the window opens
+fn startup_config() -> &'static Config {
+    static CONFIG: OnceLock<Config> = OnceLock::new();
+    CONFIG.get_or_init(|| {
+        let mut config = Config::load().unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            process::exit(1);
+        });
+        // Options like --world can make valid files invalid
+        cli::args().apply(&mut config);
+        config.validate().unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            process::exit(1);
+        });
+        config
+    })
+}
+
 fn window_config() -> Conf {
+    let window = Vec2::from(startup_config().window.size);
     Conf {
         window_title: "Particle Life".to_owned(),
-        window_width: WINDOW_SIZE_PX.x.round() as i32,
-        window_height: WINDOW_SIZE_PX.y.round() as i32,
+        window_width: window.x.round() as i32,
+        window_height: window.y.round() as i32,
         ..Default::default()
     }
 }
@@ -25,13 +47,27 @@ fn window_config() -> Conf {
 #[macroquad::main(window_config)]
 async fn main() {
     let mut stdout = io::stdout();
-    rand::srand(
+    let args = cli::args();
+
+    let seed = args.seed.unwrap_or_else(|| {
         time::SystemTime::now()
             .duration_since(time::UNIX_EPOCH)
             .expect("Time travel")
-            .as_secs(),
-    );
-    let mut game = Game::new();
+            .as_secs()
+    });
+    println!("Seed: {}", seed);
+
+    let preset = args.preset.as_ref().map(|path| {
+        Preset::load(path).unwrap_or_else(|err| {
+            eprintln!("Could not load {}: {}", path.display(), err);
+            process::exit(1);
+        })
+    });
+
+    let mut game = Game::new(startup_config().clone(), seed, preset);
+    if let Some(err) = game.threads_error() {
+        eprintln!("Could not start worker threads, running serially: {}", err);
+    }
 
     loop {
         game.handle_input();
@@ -42,7 +78,12 @@ async fn main() {
 
         // Print FPS every second
         if get_time() % 1. < get_frame_time() as f64 {
-            print!("\rFPS: {}", get_fps());
+            print!(
+                "\rFPS: {}  Steps/s: {:.0}  Threads: {}  ",
+                get_fps(),
+                game.steps_per_second(),
+                game.thread_times()
+            );
             stdout.flush().expect("Stdout flush failed");
         }
 
@@ -0,0 +1,158 @@
+use serde::{Deserialize, Serialize};
+
+use crate::defaults::*;
+use crate::force::ForceProfile;
+
+/// How positions and velocities are advanced by one time step
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Integrator {
+    /// Moves with the old velocity, then accelerates. First order.
+    ExplicitEuler,
+    /// Accelerates, moves with the new velocity, then applies friction. First order, but
+    /// doesn't gain energy the way explicit Euler does. This is what every frame did before
+    /// `dt` existed.
+    #[default]
+    SemiImplicitEuler,
+    /// Moves with the old velocity and half of the change in it, then accelerates by the
+    /// average of the old and new acceleration. Second order.
+    VelocityVerlet,
+}
+
+/// Physics constants of a single simulation.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "ParamsFile")]
+pub struct SimulationParams {
+    pub min_distance: f32, // Distance at which particles start to repel each other regardless of their attraction
+    pub max_distance: f32, // Distance at which particles stop having an effect on each other
+    pub repel_constant: f32,
+    pub attract_constant: f32,
+    pub friction: f32, // Rate at which particles lose speed, velocities decay by exp(-friction * dt)
+    pub particle_radius: f32,
+    pub dt: f32, // Seconds simulated by one step
+    pub integrator: Integrator,
+    pub force_profile: ForceProfile, // Shape of the force, see `ForceProfile`
+}
+
+// `SimulationParams` as written to files. Files from before time steps were configurable
+// leave out `dt` and the later fields, and their friction is the fraction of the velocity
+// lost every frame.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ParamsFile {
+    min_distance: f32,
+    max_distance: f32,
+    repel_constant: f32,
+    attract_constant: f32,
+    friction: f32,
+    particle_radius: f32,
+    dt: Option<f32>,
+    #[serde(default)]
+    integrator: Integrator,
+    #[serde(default)]
+    force_profile: ForceProfile,
+}
+
+impl TryFrom<ParamsFile> for SimulationParams {
+    type Error = String;
+
+    fn try_from(file: ParamsFile) -> Result<Self, String> {
+        let friction = match file.dt {
+            Some(_) => file.friction,
+            None => per_second_friction(file.friction)?,
+        };
+        Ok(Self {
+            min_distance: file.min_distance,
+            max_distance: file.max_distance,
+            repel_constant: file.repel_constant,
+            attract_constant: file.attract_constant,
+            friction,
+            particle_radius: file.particle_radius,
+            dt: file.dt.unwrap_or(DT),
+            integrator: file.integrator,
+            force_profile: file.force_profile,
+        })
+    }
+}
+
+/// Converts the fraction of the velocity lost every frame of `REFERENCE_STEP`, which is what
+/// friction was before time steps were configurable, to the rate lost per second
+pub fn per_second_friction(per_frame: f32) -> Result<f32, String> {
+    if !(0.0..=1.0).contains(&per_frame) {
+        return Err(format!(
+            "friction without dt is the fraction of the velocity lost every frame and must be \
+             in [0, 1], got {}. Set dt to give the friction per second",
+            per_frame
+        ));
+    }
+    let kept = (1. - per_frame).max(f32::MIN_POSITIVE);
+    Ok(-kept.ln() / REFERENCE_STEP)
+}
+
+impl SimulationParams {
+    /// Interaction range the grid cells have to cover.
+    pub fn max_effect_range(&self) -> f32 {
+        self.max_distance.max(self.min_distance)
+    }
+
+    /// Checks that the params describe a usable force, explaining the first problem found.
+    pub fn validate(&self) -> Result<(), String> {
+        let finite = [
+            self.min_distance,
+            self.max_distance,
+            self.repel_constant,
+            self.attract_constant,
+            self.friction,
+            self.particle_radius,
+            self.dt,
+        ]
+        .iter()
+        .all(|v| v.is_finite());
+        if !finite {
+            return Err("all values must be finite numbers".to_owned());
+        }
+
+        if !(self.min_distance > 0. && self.max_distance > self.min_distance) {
+            return Err(format!(
+                "distances must satisfy 0 < min_distance < max_distance, got {} and {}",
+                self.min_distance, self.max_distance
+            ));
+        }
+        if self.repel_constant < 0. || self.attract_constant < 0. {
+            return Err("repel_constant and attract_constant can not be negative".to_owned());
+        }
+        if self.friction < 0. {
+            return Err(format!(
+                "friction can not be negative, got {}",
+                self.friction
+            ));
+        }
+        if self.dt <= 0. {
+            return Err(format!("dt must be positive, got {}", self.dt));
+        }
+        if self.particle_radius <= 0. {
+            return Err(format!(
+                "particle_radius must be positive, got {}",
+                self.particle_radius
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for SimulationParams {
+    fn default() -> Self {
+        Self {
+            min_distance: MIN_DISTANCE,
+            max_distance: MAX_DISTNACE,
+            repel_constant: REPEL_CONSTANT,
+            attract_constant: ATTRACT_CONSTANT,
+            friction: PARTICLE_FRICTION,
+            particle_radius: PARTICLE_RADIUS,
+            dt: DT,
+            integrator: Integrator::default(),
+            force_profile: ForceProfile::default(),
+        }
+    }
+}
@@ -1,24 +1,117 @@
-use macroquad::prelude::*;
-use std::thread;
+use glam::Vec2;
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
 
-use crate::config::*;
-use crate::grid::{Cell, Grid};
-#[derive(Clone)]
+use crate::boundary::{Boundary, BoundaryMode};
+use crate::defaults::{MAX_DISTNACE, MAX_TYPES, MIN_DISTANCE, REFERENCE_STEP};
+use crate::domain::Domain;
+use crate::force::{self, ForceLaw, ForceProfile};
+use crate::grid::{wrapped_neighbours, Grid};
+use crate::kernel::{self, KernelConsts, Pair};
+use crate::params::{Integrator, SimulationParams};
+use crate::pool::WorkerPool;
+use crate::verlet::NeighbourList;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct ParticleType {
-    pub color: Color,
+    pub color: [f32; 4], // RGBA
     pub attraction: Vec<f32>,
+    // Like `attraction`, one entry per type: the distance below which the type repels this
+    // one and the distance from which on it has no effect. Presets may leave them out.
+    #[serde(default)]
+    pub min_distance: Vec<f32>,
+    #[serde(default)]
+    pub max_distance: Vec<f32>,
+    #[serde(default = "default_mass")]
+    pub mass: f32, // Forces accelerate the type by their strength divided by its mass
+    // Replace `SimulationParams::friction` and `particle_radius` for the type when set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub friction: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_speed: Option<f32>, // Per second, unlimited if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub radius: Option<f32>, // Only used for drawing
+}
+
+fn default_mass() -> f32 {
+    1.
+}
+
+/// Whether a pair of types can interact from `min_distance` to `max_distance`
+pub fn valid_radii(min_distance: f32, max_distance: f32) -> bool {
+    min_distance > 0. && max_distance > min_distance && max_distance.is_finite()
 }
 
 impl ParticleType {
-    pub fn new(color: Color, attraction: Vec<f32>) -> Self {
-        Self { color, attraction }
+    /// Interacts with every type over the default range, see `with_radii`
+    pub fn new(color: [f32; 4], attraction: Vec<f32>) -> Self {
+        Self {
+            color,
+            attraction,
+            min_distance: Vec::new(),
+            max_distance: Vec::new(),
+            mass: default_mass(),
+            friction: None,
+            max_speed: None,
+            radius: None,
+        }
+        .with_radii(MIN_DISTANCE, MAX_DISTNACE)
+    }
+
+    /// Interacts with every type from `min_distance` to `max_distance`
+    pub fn with_radii(mut self, min_distance: f32, max_distance: f32) -> Self {
+        let num_types = self.attraction.len();
+        self.min_distance = vec![min_distance; num_types];
+        self.max_distance = vec![max_distance; num_types];
+        self
+    }
+
+    /// Checks mass, friction, max speed and radius
+    pub fn validate_properties(&self) -> Result<(), String> {
+        if !(self.mass > 0. && self.mass.is_finite()) {
+            return Err(format!("mass must be positive, got {}", self.mass));
+        }
+        if let Some(friction) = self
+            .friction
+            .filter(|friction| friction.is_nan() || *friction < 0.)
+        {
+            return Err(format!("friction can not be negative, got {}", friction));
+        }
+        if let Some(max_speed) = self
+            .max_speed
+            .filter(|speed| speed.is_nan() || *speed <= 0.)
+        {
+            return Err(format!("max_speed must be positive, got {}", max_speed));
+        }
+        if let Some(radius) = self
+            .radius
+            .filter(|radius| radius.is_nan() || *radius <= 0.)
+        {
+            return Err(format!("radius must be positive, got {}", radius));
+        }
+        Ok(())
+    }
+
+    /// Drawn radius of the type's particles
+    pub fn radius(&self, params: &SimulationParams) -> f32 {
+        self.radius.unwrap_or(params.particle_radius)
+    }
+
+    /// Largest distance at which any type affects this one
+    pub fn range(&self) -> f32 {
+        self.min_distance
+            .iter()
+            .chain(&self.max_distance)
+            .fold(0., |range, &distance| range.max(distance))
     }
 }
 
+/// A single particle, as handed in and out of `Particles`
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Particle {
-    pos: Vec2,
-    vel: Vec2,
-    type_id: usize, // index of the type in the type manager
+    pub pos: Vec2,
+    pub vel: Vec2,
+    pub type_id: usize, // index of the type in the type manager
 }
 
 impl Particle {
@@ -31,157 +124,1016 @@ impl Particle {
     }
 }
 
+const DEFAULT_REORDER_INTERVAL: usize = 16;
+const DEFAULT_VERLET_SKIN: f32 = 16.;
+const TASKS_PER_THREAD: usize = 4;
+
+/// Inner loop used to sum up the forces between a particle and its neighbours
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ForceKernel {
+    /// One pair at a time
+    Scalar,
+    /// Neighbours in blocks, written so that the compiler vectorises it
+    #[default]
+    Simd,
+}
+
+/// How the neighbours of a particle are found
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NeighbourSearch {
+    /// Search the surrounding grid cells every update
+    #[default]
+    Grid,
+    /// Keep a list of neighbours per particle, only rebuilt from the grid once particles
+    /// have moved far enough. Always uses the vectorised kernel and the full stencil.
+    Verlet,
+    /// Compare every particle with every other one. Far too slow for large worlds, only
+    /// meant as a reference to check the other searches against.
+    BruteForce,
+}
+
+/// Particles stored as a structure of arrays, one array per field.
 pub struct Particles {
-    particles: Vec<Particle>,
+    x: Vec<f32>,
+    y: Vec<f32>,
+    vx: Vec<f32>,
+    vy: Vec<f32>,
+    type_id: Vec<u8>,
     num_particles: usize,
+    game_area_size: Vec2,
+    boundary: Boundary,
+    domain: Domain,
     grid: Grid,
+    grid_range: f32, // Interaction range the grid cells were sized for
+    kernel: ForceKernel,
+    half_stencil: bool,
+    reorder_interval: usize,
+    steps_until_reorder: usize,
+    neighbour_search: NeighbourSearch,
+    verlet_skin: f32,
+    neighbour_list: NeighbourList,
+    // Copies in grid order, so that the particles of a cell are contiguous
+    sorted_x: Vec<f32>,
+    sorted_y: Vec<f32>,
+    sorted_type_id: Vec<u8>,
+    forces: Vec<Vec2>, // In grid order when searching the grid, otherwise in particle order. Reused between updates
+    previous: Vec<Vec2>, // Positions before integrating, only kept for domains other than the rect
+    accel: Vec<Vec2>,  // In particle order, reused between updates
+    half_kicked: bool, // Whether the velocities still lack the second half kick of a velocity Verlet step
 }
 
 impl Particles {
-    pub fn new(game_area_size: Vec2) -> Self {
+    pub fn new(game_area_size: Vec2, params: &SimulationParams) -> Self {
         Self {
-            particles: Vec::new(),
+            x: Vec::new(),
+            y: Vec::new(),
+            vx: Vec::new(),
+            vy: Vec::new(),
+            type_id: Vec::new(),
             num_particles: 0,
-            grid: Grid::new(game_area_size, MAX_DISTNACE.max(MIN_DISTANCE)),
+            game_area_size,
+            boundary: Boundary::default(),
+            domain: Domain::default(),
+            grid: Grid::new(game_area_size, params.max_effect_range()),
+            grid_range: params.max_effect_range(),
+            kernel: ForceKernel::default(),
+            half_stencil: false,
+            reorder_interval: DEFAULT_REORDER_INTERVAL,
+            steps_until_reorder: 0,
+            neighbour_search: NeighbourSearch::default(),
+            verlet_skin: DEFAULT_VERLET_SKIN,
+            neighbour_list: NeighbourList::default(),
+            sorted_x: Vec::new(),
+            sorted_y: Vec::new(),
+            sorted_type_id: Vec::new(),
+            forces: Vec::new(),
+            previous: Vec::new(),
+            accel: Vec::new(),
+            half_kicked: false,
+        }
+    }
+
+    pub fn get(&self, index: usize) -> Particle {
+        Particle {
+            pos: Vec2::new(self.x[index], self.y[index]),
+            vel: Vec2::new(self.vx[index], self.vy[index]),
+            type_id: self.type_id[index] as usize,
         }
     }
 
+    pub fn iter(&self) -> impl Iterator<Item = Particle> + '_ {
+        (0..self.num_particles).map(|i| self.get(i))
+    }
+
+    pub fn type_ids(&self) -> &[u8] {
+        &self.type_id
+    }
+
+    pub fn type_ids_mut(&mut self) -> &mut [u8] {
+        &mut self.type_id
+    }
+
+    pub fn len(&self) -> usize {
+        self.num_particles
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.num_particles == 0
+    }
+
+    pub fn game_area_size(&self) -> Vec2 {
+        self.game_area_size
+    }
+
+    pub fn grid(&self) -> &Grid {
+        &self.grid
+    }
+
+    pub fn kernel(&self) -> ForceKernel {
+        self.kernel
+    }
+
+    pub fn set_kernel(&mut self, kernel: ForceKernel) {
+        self.kernel = kernel;
+    }
+
+    pub fn half_stencil(&self) -> bool {
+        self.half_stencil
+    }
+
+    /// Visits every pair of neighbours once instead of once from each side, computing their
+    /// distance once. The force on each side still has to be evaluated, so this is only a
+    /// little faster than visiting all neighbours, and only when every pair of types has the
+    /// same radii both ways and shares its force law. With asymmetric radii it is slower.
+    /// The forces are the same up to rounding.
+    /// Only takes effect with at least 3 grid columns and 4 grid rows, smaller grids
+    /// keep visiting all neighbours.
+    pub fn set_half_stencil(&mut self, half_stencil: bool) {
+        self.half_stencil = half_stencil;
+    }
+
+    pub fn reorder_interval(&self) -> usize {
+        self.reorder_interval
+    }
+
+    /// Every `interval` updates the particles are sorted by cell along a Z-order curve, so that
+    /// neighbours are close in memory. This changes the index of every particle.
+    /// 0 keeps the particles in the order they were added.
+    pub fn set_reorder_interval(&mut self, interval: usize) {
+        self.reorder_interval = interval;
+        self.steps_until_reorder = 0;
+    }
+
+    pub fn boundary(&self) -> Boundary {
+        self.boundary
+    }
+
+    pub fn set_boundary(&mut self, boundary: Boundary) {
+        self.boundary = boundary;
+        self.grid.set_periodic(boundary.periodic());
+        self.neighbour_list.invalidate();
+    }
+
+    pub fn domain(&self) -> &Domain {
+        &self.domain
+    }
+
+    /// Confines the particles to `domain` from the next update on. Particles that are outside
+    /// of it stay there until they happen to move in, see `Simulation::set_domain`.
+    pub fn set_domain(&mut self, domain: Domain) {
+        self.domain = domain;
+    }
+
+    /// Whether the velocities still lack the second half kick of a velocity Verlet step
+    pub fn half_kicked(&self) -> bool {
+        self.half_kicked
+    }
+
+    // For restoring snapshots, the particles have to be added first
+    pub(crate) fn set_half_kicked(&mut self, half_kicked: bool) {
+        self.half_kicked = half_kicked;
+    }
+
+    pub fn neighbour_search(&self) -> NeighbourSearch {
+        self.neighbour_search
+    }
+
+    pub fn set_neighbour_search(&mut self, neighbour_search: NeighbourSearch) {
+        // The other searches rebuild the grid the lists were built from
+        if neighbour_search != self.neighbour_search {
+            self.neighbour_list.invalidate();
+        }
+        self.neighbour_search = neighbour_search;
+    }
+
+    pub fn verlet_skin(&self) -> f32 {
+        self.verlet_skin
+    }
+
+    /// Extra distance kept in the neighbour lists. A larger skin makes the lists longer
+    /// but lets them last for more updates.
+    pub fn set_verlet_skin(&mut self, skin: f32) {
+        self.verlet_skin = skin;
+    }
+
+    // Copies the settings that aren't part of the particles themselves
+    pub(crate) fn copy_settings(&mut self, other: &Particles) {
+        self.kernel = other.kernel;
+        self.half_stencil = other.half_stencil;
+        self.reorder_interval = other.reorder_interval;
+        self.set_neighbour_search(other.neighbour_search);
+        self.verlet_skin = other.verlet_skin;
+        self.set_boundary(other.boundary);
+        self.domain = other.domain.clone();
+    }
+
+    fn uses_half_stencil(&self) -> bool {
+        self.half_stencil && self.grid.shape.0 >= 3 && self.grid.shape.1 >= 4
+    }
+
+    /// Replaces the particle at `index`, panics if the type id doesn't fit into a byte
+    pub fn set(&mut self, index: usize, particle: Particle) {
+        assert!(particle.type_id < MAX_TYPES, "type id out of range");
+        self.x[index] = particle.pos.x;
+        self.y[index] = particle.pos.y;
+        self.vx[index] = particle.vel.x;
+        self.vy[index] = particle.vel.y;
+        self.type_id[index] = particle.type_id as u8;
+        self.neighbour_list.invalidate();
+        self.half_kicked = false;
+    }
+
+    /// Panics if the type id doesn't fit into a byte, see `MAX_TYPES`
     pub fn add_particle(&mut self, particle: Particle) {
-        self.grid.insert(self.num_particles, particle.pos);
-        self.particles.push(particle);
+        assert!(particle.type_id < MAX_TYPES, "type id out of range");
+        self.x.push(particle.pos.x);
+        self.y.push(particle.pos.y);
+        self.vx.push(particle.vel.x);
+        self.vy.push(particle.vel.y);
+        self.type_id.push(particle.type_id as u8);
         self.num_particles += 1;
+        self.neighbour_list.invalidate();
+        self.half_kicked = false;
     }
 
-    pub fn update_cell(&mut self, cell: &Cell, types: &[ParticleType]) {
-        let cell_x = cell.pos.0 as isize;
-        let cell_y = cell.pos.1 as isize;
-
+    /// Adds the force on every particle of `cell` to `forces`, which has one entry
+    /// per particle in the cell.
+    fn cell_forces<F: ForceLaw>(&self, cell: usize, consts: &KernelConsts<F>, forces: &mut [Vec2]) {
         // Basically do a convolution with a 3x3 kernel over the cells and update each particle
         // in the central cell based on every particle (except itself) in every surrounding
         // cell (including its own cell).
         // This is done to avoid having to check every particle against every other particle
         // in the game.
-        // The cells wrap around the edges of the game area.
-        let cell1 = &self.grid.cells[cell_y as usize * self.grid.shape.0 + cell_x as usize];
-        for cell_i in (cell_y.overflowing_sub(1).0)..=(cell_y.overflowing_add(1).0) {
-            for cell_j in (cell_x.overflowing_sub(1).0)..=(cell_x.overflowing_add(1).0) {
-                let cell_i = cell_i.rem_euclid(self.grid.shape.1 as isize) as usize;
-                let cell_j = cell_j.rem_euclid(self.grid.shape.0 as isize) as usize;
-
-                for pi in 0..cell1.particles.len() {
-                    let cell2 = &self.grid.cells[cell_i * self.grid.shape.0 + cell_j];
-                    for pj in 0..cell2.particles.len() {
-                        let i = cell1.particles[pi];
-                        let j = cell2.particles[pj];
-                        if i == j {
-                            continue;
-                        }
-                        let type1 = &types[self.particles[i].type_id];
-                        let typeid2 = self.particles[j].type_id;
-
-                        let mut d = self.particles[j].pos - self.particles[i].pos;
-
-                        if d.x.abs() > GAME_AREA_SIZE_U.x / 2. {
-                            d.x = -d.x.signum() * (GAME_AREA_SIZE_U.x - d.x.abs());
-                        }
-                        if d.y.abs() > GAME_AREA_SIZE_U.y / 2. {
-                            d.y = -d.y.signum() * (GAME_AREA_SIZE_U.y - d.y.abs());
-                        }
-
-                        let distance = d.length();
-                        d /= distance;
-
-                        // Unsafe because of using static variables that might be changed
-                        unsafe {
-                            if distance < MIN_DISTANCE {
-                                self.particles[i].vel -=
-                                    REPEL_CONSTANT * d * (MIN_DISTANCE - distance) / MIN_DISTANCE;
-                            } else if distance < MAX_DISTNACE {
-                                let num = (distance - (MAX_DISTNACE + MIN_DISTANCE) / 2.).abs();
-                                let den = MAX_DISTNACE - MIN_DISTANCE;
-                                self.particles[i].vel += ATTRACT_CONSTANT
-                                    * d
-                                    * type1.attraction[typeid2]
-                                    * (1. - num / den);
-                            }
-                        }
+        let neighbours = self.grid.neighbour_ranges(cell);
+        let first = self.grid.cell_start()[cell];
+        for (pi, force) in forces.iter_mut().enumerate() {
+            let i = first + pi;
+            let pairs = consts.pairs(self.sorted_type_id[i] as usize);
+
+            for range in neighbours.iter().filter(|range| !range.is_empty()).cloned() {
+                match self.kernel {
+                    ForceKernel::Scalar => {
+                        self.scalar_force(i, range, pairs, consts, force);
+                    }
+                    ForceKernel::Simd => {
+                        *force += kernel::simd_force(
+                            Vec2::new(self.sorted_x[i], self.sorted_y[i]),
+                            &self.sorted_x[range.clone()],
+                            &self.sorted_y[range.clone()],
+                            &self.sorted_type_id[range],
+                            pairs,
+                            consts,
+                        );
                     }
                 }
             }
         }
     }
 
-    pub fn update(&mut self, types: &[ParticleType]) {
-        self.grid = Grid::new(GAME_AREA_SIZE_U, MAX_DISTNACE.max(MIN_DISTANCE));
-        for i in 0..self.num_particles {
-            self.grid.insert(i, self.particles[i].pos);
-        }
-
-        let self_ptr = self as *const _ as usize;
-
-        let num_cpus = (num_cpus::get().min(MAX_CORES) - 1).max(1);
-        let core_ids = core_affinity::get_core_ids().expect("Could not get core IDs");
-        let cells_per_cpu = (self.grid.cells.len() as f32 / num_cpus as f32).ceil() as usize;
-        let cell_chunks = self.grid.cells.chunks_mut(cells_per_cpu);
-
-        thread::scope(|s| {
-            for (chunk, core_id) in cell_chunks.zip(core_ids) {
-                s.spawn(move || {
-                    core_affinity::set_for_current(core_id);
-                    for cell in chunk {
-                        unsafe {
-                            // Epic way to avoid refactoring the code.
-                            // It should be safe because update_cells only needs
-                            // the position of other particles and only
-                            // changes velocities, so doing it in parallel should be okay.
-                            // The positions are updated based on their velocities later
-                            let slf = &mut *(self_ptr as *mut Self);
-
-                            slf.update_cell(cell, types);
-                        }
-                    }
+    // Adds the force on the particle at sorted index `i` from the ones at `others` to `force`
+    fn scalar_force<F: ForceLaw>(
+        &self,
+        i: usize,
+        others: Range<usize>,
+        pairs: &[Pair<F>],
+        consts: &KernelConsts<F>,
+        force: &mut Vec2,
+    ) {
+        let pos = Vec2::new(self.sorted_x[i], self.sorted_y[i]);
+        for j in others {
+            if i == j {
+                continue;
+            }
+            let mut d = Vec2::new(self.sorted_x[j], self.sorted_y[j]) - pos;
+
+            // Never true along axes that aren't periodic, their wrap size is zero
+            let size = consts.wrap_size;
+            if d.x.abs() > size.x / 2. && size.x > 0. {
+                d.x = -d.x.signum() * (size.x - d.x.abs());
+            }
+            if d.y.abs() > size.y / 2. && size.y > 0. {
+                d.y = -d.y.signum() * (size.y - d.y.abs());
+            }
+
+            let distance = d.length();
+            d /= distance;
+
+            // Pairs out of range are skipped, the vectorised kernels mask them instead
+            let pair = &pairs[self.sorted_type_id[j] as usize];
+            if distance < pair.max_distance {
+                *force += d * pair.law.magnitude(distance, pair.attraction);
+            }
+        }
+    }
+
+    // Computes the forces of a contiguous range of cells. `forces` holds one entry per
+    // particle in those cells, in cell order.
+    fn cells_forces<F: ForceLaw>(
+        &self,
+        cells: Range<usize>,
+        consts: &KernelConsts<F>,
+        forces: &mut [Vec2],
+    ) {
+        let mut offset = 0;
+        for cell in cells {
+            let len = self.grid.cell(cell).len();
+            self.cell_forces(cell, consts, &mut forces[offset..offset + len]);
+            offset += len;
+        }
+    }
+
+    // Sorted index ranges of the cells the half stencil visits from `cell`: its own cell, the
+    // cell to the right and the three cells below, as far as they exist. Cells that are next to
+    // each other in sorted order share a range, the rest stay empty. A particle only visits the
+    // particles after it in its own cell, every other neighbour visits it instead.
+    fn forward_ranges(&self, cell: usize) -> [Range<usize>; 5] {
+        let (cell_x, cell_y) = self.grid.cell_pos(cell);
+        let (width, height) = self.grid.shape;
+        let [periodic_x, periodic_y] = self.grid.periodic();
+        let cell_start = self.grid.cell_start();
+        let range = |cell2: usize| cell_start[cell2]..cell_start[cell2 + 1];
+
+        let mut ranges: [Range<usize>; 5] = Default::default();
+        if cell_x + 1 < width {
+            ranges[0] = cell_start[cell]..cell_start[cell + 2];
+        } else {
+            ranges[0] = range(cell);
+            if periodic_x {
+                ranges[1] = range(self.grid.cell_index(0, cell_y));
+            }
+        }
+        if cell_y + 1 == height && !periodic_y {
+            return ranges;
+        }
+        let y = (cell_y + 1) % height;
+        if cell_x > 0 && cell_x + 1 < width {
+            let first = self.grid.cell_index(cell_x - 1, y);
+            ranges[2] = cell_start[first]..cell_start[first + 3];
+        } else {
+            for (k, x) in wrapped_neighbours(cell_x, width, periodic_x).enumerate() {
+                ranges[k + 2] = range(self.grid.cell_index(x, y));
+            }
+        }
+
+        ranges
+    }
+
+    // Half stencil forces of all cells in `rows`, which only write to those rows, the row
+    // after them and, for the last rows, the first row
+    fn band_forces<F: ForceLaw>(
+        &self,
+        rows: Range<usize>,
+        consts: &KernelConsts<F>,
+        forces: &mut BandForces,
+    ) {
+        let cell_start = self.grid.cell_start();
+        for cell in rows.start * self.grid.shape.0..rows.end * self.grid.shape.0 {
+            let mut ranges = self.forward_ranges(cell);
+            for i in cell_start[cell]..cell_start[cell + 1] {
+                let type_id = self.sorted_type_id[i] as usize;
+                ranges[0].start = i + 1;
+
+                let mut force = Vec2::ZERO;
+                for range in ranges.iter().filter(|range| !range.is_empty()).cloned() {
+                    force += kernel::simd_pair_forces(
+                        Vec2::new(self.sorted_x[i], self.sorted_y[i]),
+                        &self.sorted_x[range.clone()],
+                        &self.sorted_y[range.clone()],
+                        &self.sorted_type_id[range.clone()],
+                        consts.pairs(type_id),
+                        consts.pairs_from(type_id),
+                        consts,
+                        forces.get_mut(range),
+                    );
+                }
+                forces.get_mut(i..i + 1)[0] += force;
+            }
+        }
+    }
+
+    // Runs the half stencil in bands of two rows (the last one may have three). A band writes
+    // into its own rows and the first row of the next band, so the bands are processed in
+    // alternating phases: even ones, then odd ones, then the last one if it would collide
+    // with the first. Every force is summed in the same order no matter how many threads run.
+    fn half_stencil_forces<F: ForceLaw>(
+        &self,
+        consts: &KernelConsts<F>,
+        forces: &mut [Vec2],
+        pool: &WorkerPool,
+    ) {
+        let (width, height) = self.grid.shape;
+        let cell_start = self.grid.cell_start();
+        let bands = height / 2;
+        let last = bands - 1;
+        let phase = |band: usize| {
+            if band == last && bands % 2 == 1 {
+                2
+            } else {
+                band % 2
+            }
+        };
+
+        for current in 0..3 {
+            let mut rest = &mut *forces;
+            let mut offset = 0;
+            let mut wrap: &mut [Vec2] = &mut [];
+            if phase(last) == current {
+                let (first_row, tail) = rest.split_at_mut(cell_start[width]);
+                wrap = first_row;
+                rest = tail;
+                offset = cell_start[width];
+            }
+
+            let tasks = (0..bands)
+                .filter(|&band| phase(band) == current)
+                .map(|band| {
+                    let rows = 2 * band..if band == last { height } else { 2 * band + 2 };
+                    let start = cell_start[rows.start * width];
+                    let end_row = if band == last { height } else { rows.end + 1 };
+                    let end = cell_start[end_row * width];
+
+                    let (_, tail) = std::mem::take(&mut rest).split_at_mut(start - offset);
+                    let (main, tail) = tail.split_at_mut(end - start);
+                    rest = tail;
+                    offset = end;
+                    let wrap = if band == last {
+                        std::mem::take(&mut wrap)
+                    } else {
+                        &mut []
+                    };
+
+                    (rows, BandForces { start, main, wrap })
                 });
+            pool.for_each(tasks, |(rows, mut band_forces)| {
+                self.band_forces(rows, consts, &mut band_forces)
+            });
+        }
+    }
+
+    // Sorts the particles by cell, with the cells in Z-order, and rebuilds the grid
+    // for the new indices
+    fn reorder(&mut self, pool: &WorkerPool) {
+        let mut order = Vec::with_capacity(self.num_particles);
+        for &cell in self.grid.morton_cells() {
+            order.extend_from_slice(self.grid.cell(cell));
+        }
+
+        fn permute<T: Copy>(values: &mut Vec<T>, order: &[usize]) {
+            *values = order.iter().map(|&i| values[i]).collect();
+        }
+        permute(&mut self.x, &order);
+        permute(&mut self.y, &order);
+        permute(&mut self.vx, &order);
+        permute(&mut self.vy, &order);
+        permute(&mut self.type_id, &order);
+        self.neighbour_list.invalidate();
+
+        let (x, y) = (&self.x, &self.y);
+        self.grid
+            .rebuild(self.num_particles, |i| Vec2::new(x[i], y[i]), pool);
+    }
+
+    // Copies positions and types into grid order
+    fn gather_sorted(&mut self, pool: &WorkerPool) {
+        let n = self.num_particles;
+        self.sorted_x.resize(n, 0.);
+        self.sorted_y.resize(n, 0.);
+        self.sorted_type_id.resize(n, 0);
+
+        let per_task = n.div_ceil(pool.threads()).max(1);
+        let (x, y, type_id) = (&self.x, &self.y, &self.type_id);
+        let index = self.grid.particle_index();
+        let chunks = self
+            .sorted_x
+            .chunks_mut(per_task)
+            .zip(self.sorted_y.chunks_mut(per_task))
+            .zip(self.sorted_type_id.chunks_mut(per_task))
+            .zip(index.chunks(per_task));
+        pool.for_each(chunks, |(((sorted_x, sorted_y), sorted_type_id), index)| {
+            for (k, &i) in index.iter().enumerate() {
+                sorted_x[k] = x[i];
+                sorted_y[k] = y[i];
+                sorted_type_id[k] = type_id[i];
             }
         });
+    }
 
-        for i in 0..self.num_particles {
-            let vel = self.particles[i].vel;
+    // Sorts the particles into a grid with cells of at least `cell_size`, reordering
+    // them first if that is due
+    fn rebuild_grid(&mut self, cell_size: f32, pool: &WorkerPool) {
+        if cell_size != self.grid_range {
+            self.grid.resize(self.game_area_size, cell_size);
+            self.grid_range = cell_size;
+        }
+        let (x, y) = (&self.x, &self.y);
+        self.grid
+            .rebuild(self.num_particles, |i| Vec2::new(x[i], y[i]), pool);
+        if self.reorder_interval > 0 && self.steps_until_reorder == 0 {
+            self.reorder(pool);
+            self.steps_until_reorder = self.reorder_interval;
+        }
+    }
+
+    pub fn update(&mut self, types: &[ParticleType], params: &SimulationParams, pool: &WorkerPool) {
+        self.steps_until_reorder = self.steps_until_reorder.saturating_sub(1);
+
+        let mut accel = std::mem::take(&mut self.accel);
+        accel.clear();
+        accel.resize(self.num_particles, Vec2::ZERO);
+        // Every profile gets kernels of its own
+        match params.force_profile {
+            ForceProfile::Triangle => {
+                self.compute_forces::<force::Triangle>(types, params, &mut accel, pool)
+            }
+            ForceProfile::Cosine => {
+                self.compute_forces::<force::Cosine>(types, params, &mut accel, pool)
+            }
+            ForceProfile::LennardJones => {
+                self.compute_forces::<force::LennardJones>(types, params, &mut accel, pool)
+            }
+            ForceProfile::InversePower => {
+                self.compute_forces::<force::InversePower>(types, params, &mut accel, pool)
+            }
+        }
+
+        let size = self.game_area_size;
+        let confined = !self.domain.is_rect();
+        if confined {
+            self.previous.clear();
+            self.previous
+                .extend(self.x.iter().zip(&self.y).map(|(&x, &y)| Vec2::new(x, y)));
+        }
+
+        let boundary = self.boundary;
+        let motion: Vec<Motion> = types
+            .iter()
+            .map(|type1| Motion::new(type1, params))
+            .collect();
+        let limited = types.iter().any(|type1| type1.max_speed.is_some());
+        for phase in [Phase::Kick, Phase::Drift] {
+            let axes = [
+                (&mut self.x, &mut self.vx, size.x, boundary.x),
+                (&mut self.y, &mut self.vy, size.y, boundary.y),
+            ];
+            for (axis, (pos, vel, size, mode)) in axes.into_iter().enumerate() {
+                let accel = Accel {
+                    accel: &accel,
+                    half_kicked: self.half_kicked,
+                    axis,
+                };
+                let type_ids = &self.type_id;
+                integrate(
+                    pos, vel, accel, type_ids, &motion, size, mode, params, phase,
+                );
+            }
+            // Before the drift so that no particle moves faster than its type allows, and
+            // after it for explicit Euler, which only kicks then
+            if limited {
+                self.limit_speeds(types);
+            }
+        }
+        self.accel = accel;
+        self.half_kicked = params.integrator == Integrator::VelocityVerlet;
+
+        if confined {
+            self.reflect_at_domain();
+        }
+        if boundary.absorbs() {
+            self.remove_outside();
+        }
+    }
 
-            self.particles[i].pos += vel;
-            // Wrap around
-            if self.particles[i].pos.x < 0. {
-                self.particles[i].pos.x = GAME_AREA_SIZE_U.x;
-            } else if self.particles[i].pos.x >= GAME_AREA_SIZE_U.x {
-                self.particles[i].pos.x = 0.;
+    // Scales velocities above the max speed of their type down to it
+    fn limit_speeds(&mut self, types: &[ParticleType]) {
+        let velocities = self.vx.iter_mut().zip(&mut self.vy);
+        for ((vx, vy), &type_id) in velocities.zip(&self.type_id) {
+            if let Some(max_speed) = types[type_id as usize].max_speed {
+                let speed = (*vx * *vx + *vy * *vy).sqrt();
+                if speed > max_speed {
+                    *vx *= max_speed / speed;
+                    *vy *= max_speed / speed;
+                }
             }
-            if self.particles[i].pos.y < 0. {
-                self.particles[i].pos.y = GAME_AREA_SIZE_U.y;
-            } else if self.particles[i].pos.y >= GAME_AREA_SIZE_U.y {
-                self.particles[i].pos.y = 0.;
+        }
+    }
+
+    // Writes the force on every particle into `accel`, in particle order
+    fn compute_forces<F: ForceLaw>(
+        &mut self,
+        types: &[ParticleType],
+        params: &SimulationParams,
+        accel: &mut [Vec2],
+        pool: &WorkerPool,
+    ) {
+        // Workers only read positions and types and write into their own part of `forces`,
+        // the particles are moved afterwards
+        let mut forces = std::mem::take(&mut self.forces);
+        forces.clear();
+        forces.resize(self.num_particles, Vec2::ZERO);
+        let wrap_size = self.boundary.wrap_size(self.game_area_size);
+        let consts = KernelConsts::<F>::new(wrap_size, params, types);
+        // The grid is sized for the pair of types with the largest range
+        let range = consts.range;
+
+        match self.neighbour_search {
+            NeighbourSearch::Grid => {
+                self.rebuild_grid(range, pool);
+                self.gather_sorted(pool);
+
+                pool.reset_busy_times();
+                if self.uses_half_stencil() {
+                    self.half_stencil_forces(&consts, &mut forces, pool);
+                } else {
+                    self.full_stencil_forces(&consts, &mut forces, pool);
+                }
+
+                // `forces` is in grid order
+                for (&i, &force) in self.grid.particle_index().iter().zip(&forces) {
+                    accel[i] = force;
+                }
             }
+            NeighbourSearch::Verlet => {
+                let skin = self.verlet_skin;
+                if self
+                    .neighbour_list
+                    .is_stale(&self.x, &self.y, wrap_size, range, skin)
+                {
+                    self.rebuild_grid(range + skin, pool);
+                    self.gather_sorted(pool);
+                    self.neighbour_list.build(
+                        &self.grid,
+                        &self.x,
+                        &self.y,
+                        &self.sorted_x,
+                        &self.sorted_y,
+                        wrap_size,
+                        range,
+                        skin,
+                        pool,
+                    );
+                } else {
+                    // The lists are in the order of the grid they were built from, which
+                    // stays as it is until they are rebuilt
+                    self.gather_sorted(pool);
+                }
+
+                pool.reset_busy_times();
+                self.list_forces(&consts, &mut forces, pool);
+
+                // `forces` is in grid order
+                for (&i, &force) in self.grid.particle_index().iter().zip(&forces) {
+                    accel[i] = force;
+                }
+            }
+            NeighbourSearch::BruteForce => {
+                pool.reset_busy_times();
+                self.brute_force_forces(&consts, &mut forces, pool);
+                accel.copy_from_slice(&forces);
+            }
+        }
+        self.forces = forces;
+    }
 
-            unsafe { self.particles[i].vel *= 1. - PARTICLE_FRICTION };
+    // Moves the particles that left the domain in the last update back in
+    fn reflect_at_domain(&mut self) {
+        let size = self.game_area_size;
+        for i in 0..self.num_particles {
+            let mut pos = Vec2::new(self.x[i], self.y[i]);
+            let mut vel = Vec2::new(self.vx[i], self.vy[i]);
+            self.domain
+                .reflect(&mut pos, &mut vel, self.previous[i], size);
+            (self.x[i], self.y[i]) = (pos.x, pos.y);
+            (self.vx[i], self.vy[i]) = (vel.x, vel.y);
         }
     }
 
-    pub fn draw(&self, types: &[ParticleType], camera: &Camera2D) {
-        // self.grid.draw();
+    // Removes the particles that left the game area along an absorbing axis, keeping the
+    // others in order
+    fn remove_outside(&mut self) {
+        let size = self.game_area_size;
+        let absorbs = [self.boundary.x, self.boundary.y].map(|mode| mode == BoundaryMode::Absorb);
+        let inside = |x: f32, y: f32| {
+            (!absorbs[0] || (0.0..size.x).contains(&x))
+                && (!absorbs[1] || (0.0..size.y).contains(&y))
+        };
+        if self.x.iter().zip(&self.y).all(|(&x, &y)| inside(x, y)) {
+            return;
+        }
 
+        let mut kept = 0;
         for i in 0..self.num_particles {
-            let particle = &self.particles[i];
-
-            // Do not draw a particle if it's not visible on camera (HUGE PERFORMANCE BOOST)
-            let pos_on_screen = camera.world_to_screen(particle.pos);
-            if pos_on_screen.x < 0.
-                || pos_on_screen.x > screen_width()
-                || pos_on_screen.y < 0.
-                || pos_on_screen.y > screen_height()
-            {
-                continue;
+            if inside(self.x[i], self.y[i]) {
+                self.x[kept] = self.x[i];
+                self.y[kept] = self.y[i];
+                self.vx[kept] = self.vx[i];
+                self.vy[kept] = self.vy[i];
+                self.type_id[kept] = self.type_id[i];
+                kept += 1;
+            }
+        }
+        self.x.truncate(kept);
+        self.y.truncate(kept);
+        self.vx.truncate(kept);
+        self.vy.truncate(kept);
+        self.type_id.truncate(kept);
+        self.num_particles = kept;
+        self.neighbour_list.invalidate();
+    }
+
+    // Running total of the estimated number of particle pairs of every cell, one entry per
+    // cell plus the total at the end. Every cell costs a little even when empty.
+    fn cell_costs(&self) -> Vec<usize> {
+        let num_cells = self.grid.num_cells();
+        let cell_start = self.grid.cell_start();
+        let cost = |cell: usize| {
+            let pairs: usize = self
+                .grid
+                .neighbour_ranges(cell)
+                .iter()
+                .map(|range| range.len())
+                .sum();
+            (cell_start[cell + 1] - cell_start[cell]) * pairs + 1
+        };
+        let mut total_cost = Vec::with_capacity(num_cells + 1);
+        total_cost.push(0);
+        for cell in 0..num_cells {
+            total_cost.push(total_cost[cell] + cost(cell));
+        }
+
+        total_cost
+    }
+
+    fn full_stencil_forces<F: ForceLaw>(
+        &self,
+        consts: &KernelConsts<F>,
+        forces: &mut [Vec2],
+        pool: &WorkerPool,
+    ) {
+        let cell_start = self.grid.cell_start();
+        let cell_costs = self.cell_costs();
+        let mut rest = forces;
+        let chunks = balanced_ranges(&cell_costs, pool).map(|cells| {
+            let len = cell_start[cells.end] - cell_start[cells.start];
+            let (chunk_forces, tail) = std::mem::take(&mut rest).split_at_mut(len);
+            rest = tail;
+            (cells, chunk_forces)
+        });
+        // Every chunk is its own task, so all of them get processed no matter how many
+        // threads actually run
+        pool.for_each(chunks, |(cells, chunk_forces)| {
+            self.cells_forces(cells, consts, chunk_forces)
+        });
+    }
+
+    // Forces from the neighbour lists, `forces` is in grid order like the lists
+    fn list_forces<F: ForceLaw>(
+        &self,
+        consts: &KernelConsts<F>,
+        forces: &mut [Vec2],
+        pool: &WorkerPool,
+    ) {
+        let mut rest = forces;
+        let chunks = balanced_ranges(self.neighbour_list.start(), pool).map(|particles| {
+            let (chunk_forces, tail) = std::mem::take(&mut rest).split_at_mut(particles.len());
+            rest = tail;
+            (particles, chunk_forces)
+        });
+        pool.for_each(chunks, |(particles, chunk_forces)| {
+            for (i, force) in particles.zip(chunk_forces) {
+                *force = kernel::simd_list_force(
+                    Vec2::new(self.sorted_x[i], self.sorted_y[i]),
+                    &self.sorted_x,
+                    &self.sorted_y,
+                    &self.sorted_type_id,
+                    self.neighbour_list.neighbours(i),
+                    consts.pairs(self.sorted_type_id[i] as usize),
+                    consts,
+                );
+            }
+        });
+    }
+
+    // Forces from every other particle, `forces` is in particle order
+    fn brute_force_forces<F: ForceLaw>(
+        &self,
+        consts: &KernelConsts<F>,
+        forces: &mut [Vec2],
+        pool: &WorkerPool,
+    ) {
+        let costs: Vec<usize> = (0..=self.num_particles).collect();
+        let mut rest = forces;
+        let chunks = balanced_ranges(&costs, pool).map(|particles| {
+            let (chunk_forces, tail) = std::mem::take(&mut rest).split_at_mut(particles.len());
+            rest = tail;
+            (particles, chunk_forces)
+        });
+        pool.for_each(chunks, |(particles, chunk_forces)| {
+            for (i, force) in particles.zip(chunk_forces) {
+                *force = kernel::simd_force(
+                    Vec2::new(self.x[i], self.y[i]),
+                    &self.x,
+                    &self.y,
+                    &self.type_id,
+                    consts.pairs(self.type_id[i] as usize),
+                    consts,
+                );
             }
+        });
+    }
+}
+
+// Splits `0..costs.len() - 1` into contiguous ranges of about equal cost, given the running
+// total `costs`. Clusters can hold most of the particles in a few cells, so equally sized
+// ranges would leave most workers idle. There are a few ranges per worker for idle workers
+// to take over.
+fn balanced_ranges<'a>(
+    costs: &'a [usize],
+    pool: &WorkerPool,
+) -> impl Iterator<Item = Range<usize>> + 'a {
+    let len = costs.len() - 1;
+    let tasks = match pool.threads() {
+        1 => 1,
+        threads => threads * TASKS_PER_THREAD,
+    };
+
+    let per_task = costs[len].div_ceil(tasks).max(1);
+    let mut first = 0;
+    std::iter::from_fn(move || {
+        if first >= len {
+            return None;
+        }
+        let target = costs[first] + per_task;
+        let end = first + costs[first..].partition_point(|&cost| cost < target);
+        let range = first..end.clamp(first + 1, len);
+        first = range.end;
+        Some(range)
+    })
+}
+
+// The part of the forces one band of the half stencil may write to
+struct BandForces<'a> {
+    start: usize,         // Sorted index of the first entry of `main`
+    main: &'a mut [Vec2], // Forces of the band's own rows and the row after them
+    wrap: &'a mut [Vec2], // Forces of the first row, for the last band
+}
+
+impl BandForces<'_> {
+    // Ranges never cross from the first row into the band's own rows
+    fn get_mut(&mut self, range: Range<usize>) -> &mut [Vec2] {
+        if range.start >= self.start {
+            &mut self.main[range.start - self.start..range.end - self.start]
+        } else {
+            &mut self.wrap[range]
+        }
+    }
+}
+
+// One axis of the forces, for `integrate`
+struct Accel<'a> {
+    accel: &'a [Vec2], // Forces from the kernels, in particle order
+    axis: usize,
+    half_kicked: bool, // See `Particles::half_kicked`
+}
+
+// Largest position inside of `[0, size)`, walls keep particles there like the other modes
+fn below(size: f32) -> f32 {
+    f32::from_bits(size.to_bits() - 1)
+}
+
+// How one type moves, for `integrate`
+#[derive(Clone, Copy)]
+struct Motion {
+    force_scale: f32, // Turns a force from the kernels into an acceleration per second squared
+    decay: f32,       // Fraction of the velocity left after a step
+    half_decay: f32,  // Fraction left after half a step
+}
 
-            let type1 = &types[particle.type_id];
-            draw_circle(particle.pos.x, particle.pos.y, PARTICLE_RADIUS, type1.color);
+impl Motion {
+    fn new(type1: &ParticleType, params: &SimulationParams) -> Self {
+        let friction = type1.friction.unwrap_or(params.friction);
+        let decay = (-friction * params.dt).exp();
+        Self {
+            force_scale: 1. / (REFERENCE_STEP * REFERENCE_STEP * type1.mass),
+            decay,
+            half_decay: decay.sqrt(),
+        }
+    }
+}
+
+// Half of a step of `integrate`. Speeds are limited in between.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    Kick,  // Changes velocities by what the integrator adds before moving
+    Drift, // Moves, then does the rest
+}
+
+// Moves one coordinate of every particle by one phase of a time step of the integrator,
+// handles the edges of the axis as `mode` says, then applies friction. Absorbed particles
+// are left outside of `[0, size)`.
+#[allow(clippy::too_many_arguments)]
+fn integrate(
+    pos: &mut [f32],
+    vel: &mut [f32],
+    accel: Accel,
+    type_ids: &[u8],
+    motion: &[Motion],
+    size: f32,
+    mode: BoundaryMode,
+    params: &SimulationParams,
+    phase: Phase,
+) {
+    let dt = params.dt;
+    for (i, (pos, vel)) in pos.iter_mut().zip(vel).enumerate() {
+        let Motion {
+            force_scale,
+            decay,
+            half_decay,
+        } = motion[type_ids[i] as usize];
+        let mut a = accel.accel[i][accel.axis];
+        if mode == BoundaryMode::SoftWall {
+            // Same repulsion as from a particle at the edge
+            let min_distance = params.min_distance;
+            let near = (min_distance - *pos).max(0.);
+            let far = (min_distance - (size - *pos)).max(0.);
+            a += params.repel_constant * (near - far) / min_distance;
+        }
+        a *= force_scale;
+
+        match (params.integrator, phase) {
+            (Integrator::ExplicitEuler, Phase::Kick) => continue,
+            (Integrator::ExplicitEuler, Phase::Drift) => {
+                *pos += *vel * dt;
+                *vel = (*vel + a * dt) * decay;
+            }
+            (Integrator::SemiImplicitEuler, Phase::Kick) => {
+                *vel += a * dt;
+                continue;
+            }
+            (Integrator::SemiImplicitEuler, Phase::Drift) => {
+                *pos += *vel * dt;
+                *vel *= decay;
+            }
+            (Integrator::VelocityVerlet, Phase::Kick) => {
+                // The second half kick of the last step needs the acceleration at the current
+                // position, so it is only done now. Friction is split around the kicks to keep
+                // the step symmetric.
+                if accel.half_kicked {
+                    *vel = (*vel + a / 2. * dt) * half_decay;
+                }
+                *vel = *vel * half_decay + a / 2. * dt;
+                continue;
+            }
+            (Integrator::VelocityVerlet, Phase::Drift) => *pos += *vel * dt,
+        }
+        match mode {
+            BoundaryMode::Periodic => {
+                if !(0.0..size).contains(pos) {
+                    // Tiny negative positions round up to `size`
+                    *pos = pos.rem_euclid(size);
+                    if *pos >= size {
+                        *pos = 0.;
+                    }
+                }
+            }
+            BoundaryMode::Reflect => {
+                if *pos < 0. {
+                    *pos = -*pos;
+                    *vel = -*vel;
+                } else if *pos >= size {
+                    *pos = 2. * size - *pos;
+                    *vel = -*vel;
+                }
+                // Fast enough to bounce past the other edge as well
+                *pos = pos.clamp(0., below(size));
+            }
+            BoundaryMode::Absorb => {}
+            BoundaryMode::SoftWall => {
+                if !(0.0..size).contains(pos) {
+                    *pos = pos.clamp(0., below(size));
+                    *vel = 0.;
+                }
+            }
         }
     }
 }
@@ -0,0 +1,133 @@
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Long-lived worker threads for the force pass, created once and reused every update.
+pub struct WorkerPool {
+    pool: Option<ThreadPool>, // None when running on the calling thread only
+    threads: usize,
+    pin: bool,             // Whether pinning was asked for
+    pinned: bool,          // Whether every worker actually was pinned
+    error: Option<String>, // Why the workers couldn't be started, if they couldn't
+    busy: Vec<AtomicU64>,  // Nanoseconds every worker spent on tasks since the last reset
+}
+
+impl WorkerPool {
+    /// `threads` workers, optionally pinned to separate cores. Pinning is skipped for
+    /// workers without a core of their own or when the system doesn't report cores.
+    /// With a single thread, or if no threads can be spawned, work runs on the caller,
+    /// see `error`.
+    pub fn new(threads: usize, pin: bool) -> Self {
+        let threads = threads.max(1);
+        if threads == 1 {
+            return Self::serial(pin, None);
+        }
+
+        let core_ids = if pin {
+            core_affinity::get_core_ids().unwrap_or_default()
+        } else {
+            Vec::new()
+        };
+
+        let pinned_workers = Arc::new(AtomicUsize::new(0));
+        let pool = {
+            let pinned_workers = Arc::clone(&pinned_workers);
+            ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .thread_name(|i| format!("particle-life-worker-{}", i))
+                .start_handler(move |i| {
+                    let core_id = core_ids.get(i);
+                    if core_id.is_some_and(|core_id| core_affinity::set_for_current(*core_id)) {
+                        pinned_workers.fetch_add(1, Ordering::Relaxed);
+                    }
+                })
+                .build()
+        };
+
+        match pool {
+            Ok(pool) => {
+                // Every worker has run its start handler once it took part in a broadcast
+                pool.broadcast(|_| ());
+                Self {
+                    pool: Some(pool),
+                    threads,
+                    pin,
+                    pinned: pinned_workers.load(Ordering::Relaxed) == threads,
+                    error: None,
+                    busy: (0..threads).map(|_| AtomicU64::new(0)).collect(),
+                }
+            }
+            Err(err) => Self::serial(pin, Some(err.to_string())),
+        }
+    }
+
+    fn serial(pin: bool, error: Option<String>) -> Self {
+        Self {
+            pool: None,
+            threads: 1,
+            pin,
+            pinned: false,
+            error,
+            busy: vec![AtomicU64::new(0)],
+        }
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    /// Whether pinning was asked for, even if it didn't happen
+    pub fn pin(&self) -> bool {
+        self.pin
+    }
+
+    /// Whether every worker is pinned to a core of its own
+    pub fn pinned(&self) -> bool {
+        self.pinned
+    }
+
+    /// Why the worker threads couldn't be started, in which case the tasks run on the caller
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+
+    /// Time every worker spent running tasks since the last `reset_busy_times`
+    pub fn busy_times(&self) -> Vec<Duration> {
+        self.busy
+            .iter()
+            .map(|nanos| Duration::from_nanos(nanos.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    pub fn reset_busy_times(&self) {
+        for nanos in &self.busy {
+            nanos.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Runs `f` on every task, spread over the workers. Returns once all of them are done.
+    pub fn for_each<T: Send>(&self, tasks: impl IntoIterator<Item = T>, f: impl Fn(T) + Sync) {
+        let timed = |task: T| {
+            let start = Instant::now();
+            f(task);
+            let worker = match self.pool {
+                Some(_) => rayon::current_thread_index().unwrap_or(0),
+                None => 0,
+            };
+            self.busy[worker].fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        };
+
+        match &self.pool {
+            None => tasks.into_iter().for_each(timed),
+            Some(pool) => {
+                let timed = &timed;
+                pool.in_place_scope(|s| {
+                    for task in tasks {
+                        s.spawn(move |_| timed(task));
+                    }
+                });
+            }
+        }
+    }
+}
@@ -0,0 +1,247 @@
+//! Human-editable rule sets: the attraction and radius matrices, type colors and physics params.
+//!
+//! Presets are stored as TOML (`.toml`) or JSON (`.json`), chosen by file extension.
+
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::defaults::MAX_TYPES;
+use crate::params::SimulationParams;
+use crate::particle::{valid_radii, ParticleType};
+use crate::simulation::Simulation;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Preset {
+    pub params: SimulationParams,
+    pub types: Vec<ParticleType>,
+}
+
+#[derive(Debug)]
+pub enum PresetError {
+    Io(io::Error),
+    UnknownFormat(PathBuf),
+    Parse(String),
+    Serialize(String),
+    Invalid(String),
+}
+
+impl fmt::Display for PresetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PresetError::Io(err) => write!(f, "{}", err),
+            PresetError::UnknownFormat(path) => {
+                write!(f, "{} is neither a .toml nor a .json file", path.display())
+            }
+            PresetError::Parse(err) => write!(f, "could not parse preset: {}", err),
+            PresetError::Serialize(err) => write!(f, "could not write preset: {}", err),
+            PresetError::Invalid(reason) => write!(f, "invalid preset: {}", reason),
+        }
+    }
+}
+
+impl Error for PresetError {}
+
+impl From<io::Error> for PresetError {
+    fn from(err: io::Error) -> Self {
+        PresetError::Io(err)
+    }
+}
+
+enum Format {
+    Toml,
+    Json,
+}
+
+impl Format {
+    fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "toml" => Some(Format::Toml),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+}
+
+impl Preset {
+    pub fn from_simulation(simulation: &Simulation) -> Self {
+        Self {
+            params: simulation.params,
+            types: simulation.types.clone(),
+        }
+    }
+
+    /// Replaces the types and params of `simulation`, keeping its particles.
+    pub fn apply(&self, simulation: &mut Simulation) {
+        simulation.params = self.params;
+        simulation.set_types(self.types.clone());
+    }
+
+    pub fn validate(&self) -> Result<(), PresetError> {
+        if self.types.is_empty() {
+            return Err(PresetError::Invalid(
+                "there has to be at least one type".to_owned(),
+            ));
+        }
+        if self.types.len() > MAX_TYPES {
+            return Err(PresetError::Invalid(format!(
+                "there can be at most {} types",
+                MAX_TYPES
+            )));
+        }
+
+        for (i, type1) in self.types.iter().enumerate() {
+            if type1.attraction.len() != self.types.len() {
+                return Err(PresetError::Invalid(format!(
+                    "type {} has {} attraction values, expected one per type ({})",
+                    i,
+                    type1.attraction.len(),
+                    self.types.len()
+                )));
+            }
+            if let Some(value) = type1
+                .attraction
+                .iter()
+                .find(|value| !(-1.0..=1.0).contains(*value))
+            {
+                return Err(PresetError::Invalid(format!(
+                    "attraction {} of type {} is outside of [-1, 1]",
+                    value, i
+                )));
+            }
+            if type1.min_distance.len() != self.types.len()
+                || type1.max_distance.len() != self.types.len()
+            {
+                return Err(PresetError::Invalid(format!(
+                    "type {} needs one min_distance and max_distance per type ({})",
+                    i,
+                    self.types.len()
+                )));
+            }
+            let mut radii = type1.min_distance.iter().zip(&type1.max_distance);
+            if let Some((min, max)) = radii.find(|(&min, &max)| !valid_radii(min, max)) {
+                return Err(PresetError::Invalid(format!(
+                    "radii of type {} must satisfy 0 < min_distance < max_distance, got {} and {}",
+                    i, min, max
+                )));
+            }
+            type1
+                .validate_properties()
+                .map_err(|err| PresetError::Invalid(format!("type {}: {}", i, err)))?;
+        }
+
+        self.params.validate().map_err(PresetError::Invalid)?;
+
+        Ok(())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, PresetError> {
+        let path = path.as_ref();
+        let format =
+            Format::from_path(path).ok_or_else(|| PresetError::UnknownFormat(path.into()))?;
+        let text = fs::read_to_string(path)?;
+
+        let mut preset: Preset = match format {
+            Format::Toml => {
+                toml::from_str(&text).map_err(|err| PresetError::Parse(err.to_string()))
+            }
+            Format::Json => {
+                serde_json::from_str(&text).map_err(|err| PresetError::Parse(err.to_string()))
+            }
+        }?;
+        preset.fill_missing_radii();
+        preset.validate()?;
+
+        Ok(preset)
+    }
+
+    // Presets from before per-pair radii only have the ones in their params
+    fn fill_missing_radii(&mut self) {
+        let params = self.params;
+        for type1 in &mut self.types {
+            if type1.min_distance.is_empty() && type1.max_distance.is_empty() {
+                *type1 = type1
+                    .clone()
+                    .with_radii(params.min_distance, params.max_distance);
+            }
+        }
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), PresetError> {
+        let path = path.as_ref();
+        let format =
+            Format::from_path(path).ok_or_else(|| PresetError::UnknownFormat(path.into()))?;
+
+        let serialize_err = |err: &dyn Error| PresetError::Serialize(err.to_string());
+        let text = match format {
+            Format::Toml => {
+                let mut value = toml::Value::try_from(self).map_err(|err| serialize_err(&err))?;
+                shorten_toml_floats(&mut value);
+                toml::to_string(&value).map_err(|err| serialize_err(&err))?
+            }
+            Format::Json => {
+                let mut value = serde_json::to_value(self).map_err(|err| serialize_err(&err))?;
+                shorten_json_floats(&mut value);
+                serde_json::to_string_pretty(&value).map_err(|err| serialize_err(&err))?
+            }
+        };
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, text)?;
+
+        Ok(())
+    }
+}
+
+// Values are stored as f32, so widening them to f64 for serialization would write
+// 0.16 as 0.1599999964237213. Going through the shortest f32 representation avoids that.
+fn shorten_float(value: f64) -> f64 {
+    (value as f32).to_string().parse().unwrap_or(value)
+}
+
+fn shorten_toml_floats(value: &mut toml::Value) {
+    match value {
+        toml::Value::Float(float) => *float = shorten_float(*float),
+        toml::Value::Array(array) => array.iter_mut().for_each(shorten_toml_floats),
+        toml::Value::Table(table) => table
+            .iter_mut()
+            .for_each(|(_, value)| shorten_toml_floats(value)),
+        _ => (),
+    }
+}
+
+fn shorten_json_floats(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Number(number) => {
+            if let Some(float) = number.as_f64().filter(|_| number.is_f64()) {
+                if let Some(shortened) = serde_json::Number::from_f64(shorten_float(float)) {
+                    *number = shortened;
+                }
+            }
+        }
+        serde_json::Value::Array(array) => array.iter_mut().for_each(shorten_json_floats),
+        serde_json::Value::Object(object) => object.values_mut().for_each(shorten_json_floats),
+        _ => (),
+    }
+}
+
+/// Every preset file in `dir`, sorted by name. A missing directory has no presets.
+pub fn list(dir: impl AsRef<Path>) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+
+    let mut presets: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file() && Format::from_path(path).is_some())
+        .collect();
+    presets.sort();
+
+    presets
+}
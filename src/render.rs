@@ -0,0 +1,42 @@
+use macroquad::prelude::*;
+
+use particle_life::grid::Grid;
+use particle_life::runner::Frame;
+
+pub fn draw_particles(frame: &Frame, camera: &Camera2D) {
+    for (&pos, &type_id) in frame.positions.iter().zip(&frame.type_ids) {
+        // Do not draw a particle if it's not visible on camera (HUGE PERFORMANCE BOOST)
+        let pos_on_screen = camera.world_to_screen(pos);
+        if pos_on_screen.x < 0.
+            || pos_on_screen.x > screen_width()
+            || pos_on_screen.y < 0.
+            || pos_on_screen.y > screen_height()
+        {
+            continue;
+        }
+
+        let type1 = &frame.types[type_id as usize];
+        draw_circle(
+            pos.x,
+            pos.y,
+            type1.radius(&frame.params),
+            Color::from(type1.color),
+        );
+    }
+}
+
+#[allow(unused)]
+pub fn draw_grid(grid: &Grid) {
+    let cell_size = grid.cell_size();
+    for cell in 0..grid.num_cells() {
+        let (x, y) = grid.cell_pos(cell);
+        draw_rectangle_lines(
+            x as f32 * cell_size.x,
+            y as f32 * cell_size.y,
+            cell_size.x,
+            cell_size.y,
+            5.,
+            WHITE,
+        );
+    }
+}
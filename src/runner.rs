@@ -0,0 +1,248 @@
+//! Steps a simulation on a thread of its own, so that slow steps don't hold up drawing.
+//!
+//! The thread steps at a target rate and publishes a `Frame` after every step. Frames are
+//! double buffered: the thread fills a frame of its own and swaps it with the published one,
+//! so readers never wait for a step and never see half of one. Everything that changes the
+//! simulation is sent over a channel and applied between steps.
+
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use glam::Vec2;
+
+use crate::params::SimulationParams;
+use crate::particle::ParticleType;
+use crate::simulation::Simulation;
+
+/// How fast the thread steps
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rate {
+    /// Steps the thread aims for every second. Slower steps slow the simulation down.
+    /// Rates that aren't positive, or too low to wait for, pause the thread.
+    pub steps_per_second: f32,
+    /// Calls to `Simulation::step` per step, each advancing it by `dt`
+    pub substeps: usize,
+}
+
+impl Rate {
+    // Time from one step to the next, `None` if the thread shouldn't step at all
+    fn interval(&self) -> Option<Duration> {
+        Duration::try_from_secs_f32(1. / self.steps_per_second)
+            .ok()
+            .filter(|_| self.steps_per_second > 0.)
+    }
+}
+
+impl Default for Rate {
+    fn default() -> Self {
+        Self {
+            steps_per_second: 60.,
+            substeps: 1,
+        }
+    }
+}
+
+/// The simulation after a step, everything needed to draw it
+#[derive(Clone, Debug, Default)]
+pub struct Frame {
+    pub positions: Vec<Vec2>,
+    pub type_ids: Vec<u8>,
+    pub types: Vec<ParticleType>,
+    pub params: SimulationParams,
+    pub world_size: Vec2,
+    pub seed: u64,
+    pub steps: u64,                // Since the thread started
+    pub steps_per_second: f32,     // Measured over about a second, zero while paused
+    pub busy_times: Vec<Duration>, // See `Simulation::busy_times`
+}
+
+impl Frame {
+    // Copies the simulation, reusing the buffers of the last frame
+    fn capture(&mut self, simulation: &Simulation) {
+        let particles = &simulation.particles;
+        self.positions.clear();
+        self.positions
+            .extend(particles.iter().map(|particle| particle.pos));
+        self.type_ids.clear();
+        self.type_ids.extend_from_slice(particles.type_ids());
+        self.types.clone_from(&simulation.types);
+        self.params = simulation.params;
+        self.world_size = simulation.world_size();
+        self.seed = simulation.seed();
+        self.busy_times = simulation.busy_times();
+    }
+}
+
+enum Command {
+    Edit(Box<dyn FnOnce(&mut Simulation) + Send>),
+    SetPaused(bool),
+    SetRate(Rate),
+    Stop,
+}
+
+#[derive(Default)]
+struct Published {
+    frame: Frame,
+    fresh: bool, // Whether `frame` was published after the last `Runner::frame`
+}
+
+/// Handle to a simulation stepping on its own thread. Dropping it stops the thread.
+pub struct Runner {
+    commands: Sender<Command>,
+    published: Arc<Mutex<Published>>,
+    frame: Frame,
+    thread: Option<JoinHandle<Simulation>>,
+}
+
+impl Runner {
+    /// Starts stepping `simulation` on a new thread
+    pub fn spawn(simulation: Simulation, rate: Rate) -> Self {
+        let mut frame = Frame::default();
+        frame.capture(&simulation);
+
+        let (commands, receiver) = mpsc::channel();
+        let published = Arc::new(Mutex::new(Published::default()));
+        let thread = {
+            let published = Arc::clone(&published);
+            thread::Builder::new()
+                .name("particle-life-simulation".to_owned())
+                .spawn(move || run(simulation, receiver, published, rate))
+                .expect("Could not start the simulation thread")
+        };
+
+        Self {
+            commands,
+            published,
+            frame,
+            thread: Some(thread),
+        }
+    }
+
+    fn send(&self, command: Command) {
+        self.commands
+            .send(command)
+            .expect("Simulation thread stopped");
+    }
+
+    /// Changes the simulation before the next step
+    pub fn edit(&self, edit: impl FnOnce(&mut Simulation) + Send + 'static) {
+        self.send(Command::Edit(Box::new(edit)));
+    }
+
+    /// Runs `read` on the simulation between two steps and waits for its result
+    pub fn read<T: Send + 'static>(
+        &self,
+        read: impl FnOnce(&Simulation) -> T + Send + 'static,
+    ) -> T {
+        let (sender, receiver) = mpsc::channel();
+        self.edit(move |simulation| {
+            let _ = sender.send(read(simulation));
+        });
+        receiver.recv().expect("Simulation thread stopped")
+    }
+
+    /// A paused simulation still applies edits and publishes frames for them
+    pub fn set_paused(&self, paused: bool) {
+        self.send(Command::SetPaused(paused));
+    }
+
+    pub fn set_rate(&self, rate: Rate) {
+        self.send(Command::SetRate(rate));
+    }
+
+    /// The newest published frame, or the last one returned if nothing was published since
+    pub fn frame(&mut self) -> &Frame {
+        let mut published = self.published.lock().expect("Simulation thread panicked");
+        if published.fresh {
+            std::mem::swap(&mut published.frame, &mut self.frame);
+            published.fresh = false;
+        }
+        &self.frame
+    }
+
+    /// Stops the thread and hands back the simulation
+    pub fn stop(mut self) -> Simulation {
+        self.join().expect("Simulation thread stopped")
+    }
+
+    fn join(&mut self) -> Option<Simulation> {
+        let thread = self.thread.take()?;
+        let _ = self.commands.send(Command::Stop);
+        thread.join().ok()
+    }
+}
+
+impl Drop for Runner {
+    fn drop(&mut self) {
+        self.join();
+    }
+}
+
+// Loop of the simulation thread, returns the simulation once stopped
+fn run(
+    mut simulation: Simulation,
+    commands: Receiver<Command>,
+    published: Arc<Mutex<Published>>,
+    mut rate: Rate,
+) -> Simulation {
+    let mut frame = Frame::default();
+    let mut paused = false;
+    let mut steps = 0;
+    let mut next_step = Instant::now();
+    let mut measured_since = Instant::now();
+    let mut measured_steps = 0;
+    let mut steps_per_second = 0.;
+
+    loop {
+        // Wait for the next step, or for as long as it takes while paused, taking commands
+        let timeout = next_step.saturating_duration_since(Instant::now());
+        let interval = rate.interval();
+        let command = if paused || interval.is_none() {
+            commands.recv().map_err(|_| RecvTimeoutError::Disconnected)
+        } else {
+            commands.recv_timeout(timeout)
+        };
+        match command {
+            Ok(Command::Edit(edit)) => edit(&mut simulation),
+            Ok(Command::SetPaused(pause)) => {
+                paused = pause;
+                next_step = Instant::now();
+                measured_since = Instant::now();
+                measured_steps = 0;
+                steps_per_second = 0.;
+            }
+            Ok(Command::SetRate(new_rate)) => rate = new_rate,
+            Ok(Command::Stop) | Err(RecvTimeoutError::Disconnected) => break,
+            Err(RecvTimeoutError::Timeout) => {
+                for _ in 0..rate.substeps {
+                    simulation.step();
+                }
+                steps += 1;
+                measured_steps += 1;
+
+                let now = Instant::now();
+                let elapsed = now - measured_since;
+                if elapsed >= Duration::from_secs(1) {
+                    steps_per_second = measured_steps as f32 / elapsed.as_secs_f32();
+                    measured_since = now;
+                    measured_steps = 0;
+                }
+
+                // Falling behind skips the missed steps instead of catching up
+                next_step += interval.unwrap_or_default();
+                next_step = next_step.max(now);
+            }
+        }
+
+        frame.capture(&simulation);
+        frame.steps = steps;
+        frame.steps_per_second = steps_per_second;
+        let mut published = published.lock().expect("Frame reader panicked");
+        std::mem::swap(&mut published.frame, &mut frame);
+        published.fresh = true;
+    }
+
+    simulation
+}
@@ -0,0 +1,366 @@
+use glam::Vec2;
+use rand::{Rng, SeedableRng};
+use rand_pcg::Pcg64Mcg;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use crate::defaults::*;
+use crate::domain::{Domain, DomainError};
+use crate::params::SimulationParams;
+use crate::particle::{NeighbourSearch, Particle, ParticleType, Particles};
+use crate::pool::WorkerPool;
+
+/// A complete particle life world that can be stepped without a window.
+pub struct Simulation {
+    pub particles: Particles,
+    pub types: Vec<ParticleType>,
+    pub params: SimulationParams,
+    world_size: Vec2,
+    num_particles: usize,
+    seed: u64,
+    rng: Pcg64Mcg,
+    threads: usize, // Workers the pool is started with, see `set_threads`
+    pin: bool,
+    pool: OnceLock<WorkerPool>, // Started when first needed
+}
+
+impl Simulation {
+    pub fn new(
+        world_size: Vec2,
+        num_particles: usize,
+        colors: &[[f32; 4]],
+        params: SimulationParams,
+        seed: u64,
+    ) -> Self {
+        let mut rng = Pcg64Mcg::seed_from_u64(seed);
+        let types = Self::initialize_types(&mut rng, colors, &params);
+        let particles = Self::initialize_particles(
+            &mut rng,
+            world_size,
+            num_particles,
+            &types,
+            &params,
+            &Domain::Rect,
+        );
+
+        Self {
+            particles,
+            types,
+            params,
+            world_size,
+            num_particles,
+            seed,
+            rng,
+            threads: default_threads(),
+            pin: false,
+            pool: OnceLock::new(),
+        }
+    }
+
+    /// Assembles a simulation from existing state, e.g. a loaded snapshot.
+    /// The RNG restarts from `seed`.
+    pub fn from_parts(
+        particles: Particles,
+        types: Vec<ParticleType>,
+        params: SimulationParams,
+        world_size: Vec2,
+        seed: u64,
+    ) -> Self {
+        Self {
+            num_particles: particles.len(),
+            particles,
+            types,
+            params,
+            world_size,
+            seed,
+            rng: Pcg64Mcg::seed_from_u64(seed),
+            threads: default_threads(),
+            pin: false,
+            pool: OnceLock::new(),
+        }
+    }
+
+    // Random attractions, every pair interacts over the range of `params`
+    fn initialize_types(
+        rng: &mut Pcg64Mcg,
+        colors: &[[f32; 4]],
+        params: &SimulationParams,
+    ) -> Vec<ParticleType> {
+        let mut types = Vec::new();
+
+        for color in colors {
+            let mut attract_vec = Vec::new();
+            for _ in 0..colors.len() {
+                attract_vec.push(rng.gen_range(-1.0..1.0));
+            }
+
+            types.push(
+                ParticleType::new(*color, attract_vec)
+                    .with_radii(params.min_distance, params.max_distance),
+            );
+        }
+
+        types
+    }
+
+    fn initialize_particles(
+        rng: &mut Pcg64Mcg,
+        world_size: Vec2,
+        num_particles: usize,
+        types: &[ParticleType],
+        params: &SimulationParams,
+        domain: &Domain,
+    ) -> Particles {
+        let mut particles = Particles::new(world_size, params);
+
+        for _ in 0..num_particles {
+            let pos = domain.random_position(rng, world_size);
+            particles.add_particle(Particle::new(
+                pos.to_array(),
+                [0., 0.],
+                rng.gen_range(0..types.len()),
+            ));
+        }
+
+        particles
+    }
+
+    /// Adds a type with random attractions to and from every existing type, interacting with
+    /// them over the range of the params. Returns false if there already are `MAX_TYPES` types.
+    pub fn add_type(&mut self, color: [f32; 4]) -> bool {
+        if self.types.len() >= MAX_TYPES {
+            return false;
+        }
+        let params = &self.params;
+        for type1 in &mut self.types {
+            type1.attraction.push(self.rng.gen_range(-1.0..1.0));
+            type1.min_distance.push(params.min_distance);
+            type1.max_distance.push(params.max_distance);
+        }
+        let attraction = (0..=self.types.len())
+            .map(|_| self.rng.gen_range(-1.0..1.0))
+            .collect();
+        self.types.push(
+            ParticleType::new(color, attraction)
+                .with_radii(params.min_distance, params.max_distance),
+        );
+
+        true
+    }
+
+    /// Removes a type and gives its particles a random remaining type.
+    /// The last type can not be removed, returns whether anything was removed.
+    pub fn remove_type(&mut self, type_id: usize) -> bool {
+        if self.types.len() <= 1 || type_id >= self.types.len() {
+            return false;
+        }
+
+        self.types.remove(type_id);
+        for type1 in &mut self.types {
+            type1.attraction.remove(type_id);
+            type1.min_distance.remove(type_id);
+            type1.max_distance.remove(type_id);
+        }
+
+        let num_types = self.types.len();
+        let type_id = type_id as u8;
+        for particle_type in self.particles.type_ids_mut() {
+            if *particle_type == type_id {
+                *particle_type = self.rng.gen_range(0..num_types) as u8;
+            } else if *particle_type > type_id {
+                *particle_type -= 1;
+            }
+        }
+
+        true
+    }
+
+    /// Replaces all types. Particles whose type no longer exists get a random one.
+    pub fn set_types(&mut self, types: Vec<ParticleType>) {
+        let num_types = types.len();
+        for particle_type in self.particles.type_ids_mut() {
+            if *particle_type as usize >= num_types {
+                *particle_type = self.rng.gen_range(0..num_types) as u8;
+            }
+        }
+        self.types = types;
+    }
+
+    fn colors(&self) -> Vec<[f32; 4]> {
+        self.types.iter().map(|t| t.color).collect()
+    }
+
+    pub fn world_size(&self) -> Vec2 {
+        self.world_size
+    }
+
+    // The worker pool, started on first use
+    fn pool(&self) -> &WorkerPool {
+        self.pool
+            .get_or_init(|| WorkerPool::new(self.threads, self.pin))
+    }
+
+    /// Worker threads the force pass runs on, 1 if they couldn't be started
+    pub fn threads(&self) -> usize {
+        self.pool().threads()
+    }
+
+    pub fn pinned(&self) -> bool {
+        self.pool().pinned()
+    }
+
+    /// Why the worker threads couldn't be started, the force pass then runs serially
+    pub fn threads_error(&self) -> Option<&str> {
+        self.pool().error()
+    }
+
+    /// Sets the worker threads the force pass is split over, optionally pinned to cores of
+    /// their own. A new pool is started on the next step if the settings changed. By default
+    /// there are `default_threads()` workers, not pinned.
+    pub fn set_threads(&mut self, threads: usize, pin: bool) {
+        // Compared with what was asked for, pinning that failed would fail again
+        let threads = threads.max(1);
+        if threads != self.threads || pin != self.pin {
+            self.threads = threads;
+            self.pin = pin;
+            self.pool = OnceLock::new();
+        }
+    }
+
+    /// Time every worker thread spent computing forces in the last step
+    pub fn busy_times(&self) -> Vec<Duration> {
+        self.pool().busy_times()
+    }
+
+    /// Moves the worker pool over from another simulation, e.g. one being replaced by a loaded snapshot
+    pub fn take_pool(&mut self, other: &mut Simulation) {
+        std::mem::swap(&mut self.threads, &mut other.threads);
+        std::mem::swap(&mut self.pin, &mut other.pin);
+        std::mem::swap(&mut self.pool, &mut other.pool);
+    }
+
+    /// Confines the particles to `domain`, moving the ones outside of it to random positions
+    /// inside. Fails and keeps the current domain if it doesn't fit into the world, see
+    /// `Domain::fits`.
+    pub fn set_domain(&mut self, domain: Domain) -> Result<(), DomainError> {
+        if !domain.fits(self.world_size) {
+            return Err(DomainError::Invalid(format!(
+                "the domain leaves no room for particles in a world of {} by {}",
+                self.world_size.x, self.world_size.y
+            )));
+        }
+        for i in 0..self.particles.len() {
+            let mut particle = self.particles.get(i);
+            if !domain.contains(particle.pos, self.world_size) {
+                particle.pos = domain.random_position(&mut self.rng, self.world_size);
+                particle.vel = Vec2::ZERO;
+                self.particles.set(i, particle);
+            }
+        }
+        self.particles.set_domain(domain);
+
+        Ok(())
+    }
+
+    /// Seed the current types and particles were generated from.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Regenerates types and particles exactly as `Simulation::new` would with this seed.
+    pub fn reseed(&mut self, seed: u64) {
+        let colors = self.colors();
+        self.seed = seed;
+        self.rng = Pcg64Mcg::seed_from_u64(seed);
+        self.types = Self::initialize_types(&mut self.rng, &colors, &self.params);
+        self.randomize_particles();
+    }
+
+    /// Reseeds with a new seed drawn from the current one, so the result can still be reproduced.
+    pub fn randomize_all(&mut self) {
+        let seed = self.rng.gen();
+        self.reseed(seed);
+    }
+
+    /// Respawns every particle at a random position with a random type.
+    pub fn randomize_particles(&mut self) {
+        let mut particles = Self::initialize_particles(
+            &mut self.rng,
+            self.world_size,
+            self.num_particles,
+            &self.types,
+            &self.params,
+            self.particles.domain(),
+        );
+        particles.copy_settings(&self.particles);
+        self.particles = particles;
+    }
+
+    /// Gives every type pair a uniformly random attraction in [-1, 1], keeping their radii.
+    pub fn randomize_types(&mut self) {
+        for type1 in &mut self.types {
+            for attraction in &mut type1.attraction {
+                *attraction = self.rng.gen_range(-1.0..1.0);
+            }
+        }
+    }
+
+    /// Sets every attraction to zero, keeping the radii.
+    pub fn clear_types(&mut self) {
+        for type1 in &mut self.types {
+            type1.attraction.fill(0.);
+        }
+    }
+
+    pub fn step(&mut self) {
+        let pool = self
+            .pool
+            .get_or_init(|| WorkerPool::new(self.threads, self.pin));
+        self.particles.update(&self.types, &self.params, pool);
+    }
+
+    /// Steps a copy of the particles with the current settings and one with the brute force
+    /// search side by side, returning the largest difference in velocity after every step.
+    /// Neither copy is reordered, so that particles can be compared by index.
+    pub fn reference_divergence(&self, steps: usize) -> Vec<f32> {
+        let copy = |neighbour_search| {
+            let mut particles = Particles::new(self.world_size, &self.params);
+            for particle in self.particles.iter() {
+                particles.add_particle(particle);
+            }
+            particles.copy_settings(&self.particles);
+            particles.set_reorder_interval(0);
+            if let Some(neighbour_search) = neighbour_search {
+                particles.set_neighbour_search(neighbour_search);
+            }
+            particles
+        };
+        let mut tested = copy(None);
+        let mut reference = copy(Some(NeighbourSearch::BruteForce));
+        let pool = self.pool();
+
+        (0..steps)
+            .map(|_| {
+                tested.update(&self.types, &self.params, pool);
+                reference.update(&self.types, &self.params, pool);
+                tested
+                    .iter()
+                    .zip(reference.iter())
+                    .map(|(a, b)| (a.vel - b.vel).length())
+                    .fold(0., f32::max)
+            })
+            .collect()
+    }
+}
+
+impl Default for Simulation {
+    fn default() -> Self {
+        Self::new(
+            GAME_AREA_SIZE_U,
+            NUM_PARTICLES,
+            &COLORS,
+            SimulationParams::default(),
+            0,
+        )
+    }
+}
@@ -0,0 +1,421 @@
+//! Binary save files containing the complete state of a simulation.
+//!
+//! Layout (all numbers little endian):
+//! - magic `PLSN`
+//! - format version (`u32`)
+//! - payload length (`u64`)
+//! - payload
+//! - CRC-32 of the payload (`u32`)
+//!
+//! The payload holds the world size, physics params, seed, every type, the boundary, the
+//! domain and every particle. Neighbour search settings, threads and the order of the
+//! particles in memory only change how fast a step is and are not stored.
+
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use glam::Vec2;
+
+use crate::boundary::{Boundary, BoundaryMode};
+use crate::defaults::MAX_TYPES;
+use crate::domain::{Domain, DomainShape, Mask};
+use crate::force::ForceProfile;
+use crate::params::{Integrator, SimulationParams};
+use crate::particle::{valid_radii, Particle, ParticleType, Particles};
+use crate::simulation::Simulation;
+
+const MAGIC: [u8; 4] = *b"PLSN";
+pub const VERSION: u32 = 1;
+// A world of the default size has a few hundred cells, this is far more than any screen shows
+const MAX_GRID_CELLS: f32 = (1 << 24) as f32;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u32),
+    ChecksumMismatch,
+    Corrupt(&'static str),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotError::Io(err) => write!(f, "{}", err),
+            SnapshotError::BadMagic => write!(f, "not a snapshot file"),
+            SnapshotError::UnsupportedVersion(version) => write!(
+                f,
+                "snapshot version {} is not supported (expected {})",
+                version, VERSION
+            ),
+            SnapshotError::ChecksumMismatch => write!(f, "snapshot checksum does not match"),
+            SnapshotError::Corrupt(reason) => write!(f, "corrupt snapshot: {}", reason),
+        }
+    }
+}
+
+impl Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+    fn from(err: io::Error) -> Self {
+        SnapshotError::Io(err)
+    }
+}
+
+pub fn save(simulation: &Simulation, path: impl AsRef<Path>) -> Result<(), SnapshotError> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    write(simulation, &mut writer)?;
+    writer.flush()?;
+    Ok(())
+}
+
+pub fn load(path: impl AsRef<Path>) -> Result<Simulation, SnapshotError> {
+    read(&mut BufReader::new(File::open(path)?))
+}
+
+pub fn write(simulation: &Simulation, writer: &mut impl Write) -> Result<(), SnapshotError> {
+    let payload = encode(simulation);
+
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&VERSION.to_le_bytes())?;
+    writer.write_all(&(payload.len() as u64).to_le_bytes())?;
+    writer.write_all(&payload)?;
+    writer.write_all(&crc32fast::hash(&payload).to_le_bytes())?;
+    Ok(())
+}
+
+pub fn read(reader: &mut impl Read) -> Result<Simulation, SnapshotError> {
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Err(SnapshotError::BadMagic);
+    }
+
+    let mut header = [0; 12];
+    reader.read_exact(&mut header)?;
+    let version = u32::from_le_bytes(header[0..4].try_into().unwrap());
+    if version != VERSION {
+        return Err(SnapshotError::UnsupportedVersion(version));
+    }
+    let payload_len = u64::from_le_bytes(header[4..12].try_into().unwrap());
+
+    let mut payload = Vec::new();
+    reader.take(payload_len).read_to_end(&mut payload)?;
+    if payload.len() as u64 != payload_len {
+        return Err(SnapshotError::Corrupt("file is truncated"));
+    }
+
+    let mut checksum = [0; 4];
+    reader.read_exact(&mut checksum)?;
+    if u32::from_le_bytes(checksum) != crc32fast::hash(&payload) {
+        return Err(SnapshotError::ChecksumMismatch);
+    }
+
+    decode(&payload)
+}
+
+fn encode(simulation: &Simulation) -> Vec<u8> {
+    let mut out = Vec::new();
+    let put_f32 = |out: &mut Vec<u8>, value: f32| out.extend_from_slice(&value.to_le_bytes());
+
+    let world_size = simulation.world_size();
+    put_f32(&mut out, world_size.x);
+    put_f32(&mut out, world_size.y);
+
+    let params = &simulation.params;
+    for value in [
+        params.min_distance,
+        params.max_distance,
+        params.repel_constant,
+        params.attract_constant,
+        params.friction,
+        params.particle_radius,
+        params.dt,
+    ] {
+        put_f32(&mut out, value);
+    }
+    let integrator: u32 = match params.integrator {
+        Integrator::ExplicitEuler => 0,
+        Integrator::SemiImplicitEuler => 1,
+        Integrator::VelocityVerlet => 2,
+    };
+    out.extend_from_slice(&integrator.to_le_bytes());
+    let force_profile: u32 = match params.force_profile {
+        ForceProfile::Triangle => 0,
+        ForceProfile::Cosine => 1,
+        ForceProfile::LennardJones => 2,
+        ForceProfile::InversePower => 3,
+    };
+    out.extend_from_slice(&force_profile.to_le_bytes());
+
+    out.extend_from_slice(&simulation.seed().to_le_bytes());
+
+    out.extend_from_slice(&(simulation.types.len() as u32).to_le_bytes());
+    for type1 in &simulation.types {
+        for value in type1.color {
+            put_f32(&mut out, value);
+        }
+        for row in [&type1.attraction, &type1.min_distance, &type1.max_distance] {
+            for &value in row {
+                put_f32(&mut out, value);
+            }
+        }
+        put_f32(&mut out, type1.mass);
+        // Unset properties are written as a zero flag and a zero
+        for value in [type1.friction, type1.max_speed, type1.radius] {
+            out.extend_from_slice(&(value.is_some() as u32).to_le_bytes());
+            put_f32(&mut out, value.unwrap_or_default());
+        }
+    }
+
+    let particles = &simulation.particles;
+    let boundary = particles.boundary();
+    for mode in [boundary.x, boundary.y] {
+        let mode: u32 = match mode {
+            BoundaryMode::Periodic => 0,
+            BoundaryMode::Reflect => 1,
+            BoundaryMode::Absorb => 2,
+            BoundaryMode::SoftWall => 3,
+        };
+        out.extend_from_slice(&mode.to_le_bytes());
+    }
+    match particles.domain() {
+        Domain::Rect => out.extend_from_slice(&0u32.to_le_bytes()),
+        Domain::Disk { radius } => {
+            out.extend_from_slice(&1u32.to_le_bytes());
+            put_f32(&mut out, *radius);
+        }
+        Domain::Annulus {
+            inner_radius,
+            outer_radius,
+        } => {
+            out.extend_from_slice(&2u32.to_le_bytes());
+            put_f32(&mut out, *inner_radius);
+            put_f32(&mut out, *outer_radius);
+        }
+        Domain::Mask(mask) => {
+            out.extend_from_slice(&3u32.to_le_bytes());
+            out.extend_from_slice(&(mask.width() as u32).to_le_bytes());
+            out.extend_from_slice(&(mask.height() as u32).to_le_bytes());
+            out.extend(mask.pixels().iter().map(|&inside| inside as u8));
+        }
+    }
+    out.extend_from_slice(&(particles.half_kicked() as u32).to_le_bytes());
+
+    out.extend_from_slice(&(particles.len() as u64).to_le_bytes());
+    for particle in particles.iter() {
+        put_f32(&mut out, particle.pos.x);
+        put_f32(&mut out, particle.pos.y);
+        put_f32(&mut out, particle.vel.x);
+        put_f32(&mut out, particle.vel.y);
+        out.extend_from_slice(&(particle.type_id as u32).to_le_bytes());
+    }
+
+    out
+}
+
+struct Cursor<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Cursor<'a> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], SnapshotError> {
+        if self.bytes.len() < N {
+            return Err(SnapshotError::Corrupt("payload is too short"));
+        }
+        let (head, tail) = self.bytes.split_at(N);
+        self.bytes = tail;
+        Ok(head.try_into().unwrap())
+    }
+
+    fn f32(&mut self) -> Result<f32, SnapshotError> {
+        Ok(f32::from_le_bytes(self.take()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, SnapshotError> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+
+    fn u64(&mut self) -> Result<u64, SnapshotError> {
+        Ok(u64::from_le_bytes(self.take()?))
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], SnapshotError> {
+        if self.bytes.len() < len {
+            return Err(SnapshotError::Corrupt("payload is too short"));
+        }
+        let (head, tail) = self.bytes.split_at(len);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    fn boundary_mode(&mut self) -> Result<BoundaryMode, SnapshotError> {
+        match self.u32()? {
+            0 => Ok(BoundaryMode::Periodic),
+            1 => Ok(BoundaryMode::Reflect),
+            2 => Ok(BoundaryMode::Absorb),
+            3 => Ok(BoundaryMode::SoftWall),
+            _ => Err(SnapshotError::Corrupt("unknown boundary mode")),
+        }
+    }
+
+    fn domain(&mut self) -> Result<Domain, SnapshotError> {
+        let shape = match self.u32()? {
+            0 => DomainShape::Rect,
+            1 => DomainShape::Disk {
+                radius: self.f32()?,
+            },
+            2 => DomainShape::Annulus {
+                inner_radius: self.f32()?,
+                outer_radius: self.f32()?,
+            },
+            3 => {
+                let (width, height) = (self.u32()? as u64, self.u32()? as u64);
+                // Checked before allocating so that bogus sizes can't exhaust memory
+                if width * height > self.bytes.len() as u64 {
+                    return Err(SnapshotError::Corrupt("payload is too short"));
+                }
+                let pixels = self.bytes((width * height) as usize)?;
+                let inside = pixels.iter().map(|&pixel| pixel != 0).collect();
+                let mask = Mask::from_pixels(width as usize, height as usize, inside)
+                    .ok_or(SnapshotError::Corrupt("mask has no white pixels"))?;
+                return Ok(Domain::Mask(mask));
+            }
+            _ => return Err(SnapshotError::Corrupt("unknown domain")),
+        };
+        Domain::load(&shape).map_err(|_| SnapshotError::Corrupt("invalid domain"))
+    }
+
+    fn option_f32(&mut self) -> Result<Option<f32>, SnapshotError> {
+        let is_set = self.u32()? != 0;
+        let value = self.f32()?;
+        Ok(is_set.then_some(value))
+    }
+}
+
+fn decode(payload: &[u8]) -> Result<Simulation, SnapshotError> {
+    let mut cursor = Cursor { bytes: payload };
+
+    let world_size = Vec2::new(cursor.f32()?, cursor.f32()?);
+    if !(world_size.is_finite() && world_size.x > 0. && world_size.y > 0.) {
+        return Err(SnapshotError::Corrupt(
+            "world size must be positive and finite",
+        ));
+    }
+
+    let params = SimulationParams {
+        min_distance: cursor.f32()?,
+        max_distance: cursor.f32()?,
+        repel_constant: cursor.f32()?,
+        attract_constant: cursor.f32()?,
+        friction: cursor.f32()?,
+        particle_radius: cursor.f32()?,
+        dt: cursor.f32()?,
+        integrator: match cursor.u32()? {
+            0 => Integrator::ExplicitEuler,
+            1 => Integrator::SemiImplicitEuler,
+            2 => Integrator::VelocityVerlet,
+            _ => return Err(SnapshotError::Corrupt("unknown integrator")),
+        },
+        force_profile: match cursor.u32()? {
+            0 => ForceProfile::Triangle,
+            1 => ForceProfile::Cosine,
+            2 => ForceProfile::LennardJones,
+            3 => ForceProfile::InversePower,
+            _ => return Err(SnapshotError::Corrupt("unknown force profile")),
+        },
+    };
+    if params.dt.is_nan() || params.dt <= 0. {
+        return Err(SnapshotError::Corrupt("time step must be positive"));
+    }
+    if params.validate().is_err() {
+        return Err(SnapshotError::Corrupt("invalid physics params"));
+    }
+    // Checked before building the grid so that a bogus world size can't exhaust memory
+    let cells = |length: f32| (length / params.max_effect_range()).floor().max(1.);
+    if cells(world_size.x) * cells(world_size.y) > MAX_GRID_CELLS {
+        return Err(SnapshotError::Corrupt("world is too large"));
+    }
+
+    let seed = cursor.u64()?;
+
+    let num_types = cursor.u32()? as usize;
+    if num_types == 0 {
+        return Err(SnapshotError::Corrupt("there has to be at least one type"));
+    }
+    if num_types > MAX_TYPES {
+        return Err(SnapshotError::Corrupt("too many types"));
+    }
+    // Checked before allocating so that a bogus count can't exhaust memory
+    if (num_types as u64).pow(2) > cursor.bytes.len() as u64 {
+        return Err(SnapshotError::Corrupt("payload is too short"));
+    }
+    let mut types = Vec::with_capacity(num_types);
+    for _ in 0..num_types {
+        let color = [cursor.f32()?, cursor.f32()?, cursor.f32()?, cursor.f32()?];
+        let mut row = || -> Result<Vec<f32>, SnapshotError> {
+            (0..num_types).map(|_| cursor.f32()).collect()
+        };
+        let attraction = row()?;
+        let mut type1 = ParticleType {
+            min_distance: row()?,
+            max_distance: row()?,
+            ..ParticleType::new(color, attraction)
+        };
+        let mut radii = type1.min_distance.iter().zip(&type1.max_distance);
+        if !radii.all(|(&min, &max)| valid_radii(min, max)) {
+            return Err(SnapshotError::Corrupt(
+                "radii must satisfy 0 < min_distance < max_distance",
+            ));
+        }
+        type1.mass = cursor.f32()?;
+        type1.friction = cursor.option_f32()?;
+        type1.max_speed = cursor.option_f32()?;
+        type1.radius = cursor.option_f32()?;
+        if type1.validate_properties().is_err() {
+            return Err(SnapshotError::Corrupt("type has invalid properties"));
+        }
+        types.push(type1);
+    }
+
+    let boundary = Boundary::new(cursor.boundary_mode()?, cursor.boundary_mode()?);
+    let domain = cursor.domain()?;
+    if !domain.fits(world_size) {
+        return Err(SnapshotError::Corrupt("domain doesn't fit into the world"));
+    }
+    let half_kicked = cursor.u32()? != 0;
+
+    let num_particles = cursor.u64()?;
+    let mut particles = Particles::new(world_size, &params);
+    particles.set_boundary(boundary);
+    for _ in 0..num_particles {
+        let pos = [cursor.f32()?, cursor.f32()?];
+        let vel = [cursor.f32()?, cursor.f32()?];
+        let type_id = cursor.u32()? as usize;
+        if type_id >= num_types {
+            return Err(SnapshotError::Corrupt("particle has an unknown type"));
+        }
+        if !(0. ..=world_size.x).contains(&pos[0]) || !(0. ..=world_size.y).contains(&pos[1]) {
+            return Err(SnapshotError::Corrupt("particle is outside of the world"));
+        }
+        if !domain.contains(Vec2::from(pos), world_size) {
+            return Err(SnapshotError::Corrupt("particle is outside of the domain"));
+        }
+        particles.add_particle(Particle::new(pos, vel, type_id));
+    }
+    particles.set_domain(domain);
+    particles.set_half_kicked(half_kicked && params.integrator == Integrator::VelocityVerlet);
+
+    if !cursor.bytes.is_empty() {
+        return Err(SnapshotError::Corrupt(
+            "unexpected data after the particles",
+        ));
+    }
+
+    Ok(Simulation::from_parts(
+        particles, types, params, world_size, seed,
+    ))
+}
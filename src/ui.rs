@@ -1,9 +1,12 @@
 use macroquad::prelude::*;
 use macroquad::ui::{hash, root_ui};
+use particle_life::particle::ParticleType;
+use particle_life::runner::Frame;
+use particle_life::{preset, ForceProfile, SimulationParams};
+use std::path::PathBuf;
 
-use crate::config::*;
+use crate::config::{Config, MenuConfig};
 use crate::helpers::ClickType;
-use crate::particle::ParticleType;
 
 #[derive(Clone, Copy)]
 pub struct Area {
@@ -27,6 +30,103 @@ impl Area {
     }
 }
 
+/// Matrix of `ParticleType` shown and edited by the menu grid
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Matrix {
+    Attraction,
+    MinDistance,
+    MaxDistance,
+}
+
+impl Matrix {
+    pub const ALL: [Matrix; 3] = [Matrix::Attraction, Matrix::MinDistance, Matrix::MaxDistance];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Matrix::Attraction => "Attraction",
+            Matrix::MinDistance => "Min radius",
+            Matrix::MaxDistance => "Max radius",
+        }
+    }
+
+    fn row(self, type1: &ParticleType) -> &[f32] {
+        match self {
+            Matrix::Attraction => &type1.attraction,
+            Matrix::MinDistance => &type1.min_distance,
+            Matrix::MaxDistance => &type1.max_distance,
+        }
+    }
+}
+
+/// A click on an entry of the menu grid, applied to the types of the simulation
+#[derive(Clone, Copy, Debug)]
+pub struct MatrixEdit {
+    pub matrix: Matrix,
+    pub row: usize,
+    pub col: usize,
+    pub change: f32,
+}
+
+impl MatrixEdit {
+    /// Changes the entry, keeping attractions in [-1, 1] and every min radius at least one
+    /// unit below its max radius. Entries of types that no longer exist are ignored.
+    pub fn apply(&self, types: &mut [ParticleType]) {
+        let type1 = match types.get_mut(self.row) {
+            Some(type1) if self.col < type1.attraction.len() => type1,
+            _ => return,
+        };
+        let (col, change) = (self.col, self.change);
+        match self.matrix {
+            Matrix::Attraction => {
+                let attr = &mut type1.attraction[col];
+                *attr = (*attr + change).clamp(-1., 1.);
+            }
+            Matrix::MinDistance => {
+                let max = type1.max_distance[col].max(2.);
+                let min = &mut type1.min_distance[col];
+                *min = (*min + change).clamp(1., max - 1.);
+            }
+            Matrix::MaxDistance => {
+                let min = type1.min_distance[col];
+                let max = &mut type1.max_distance[col];
+                *max = (*max + change).max(min + 1.);
+            }
+        }
+    }
+}
+
+/// New mass, friction, max speed and radius of a type, from the type window
+#[derive(Clone, Copy, Debug)]
+pub struct TypeEdit {
+    pub type_id: usize,
+    pub mass: f32,
+    pub friction: Option<f32>,
+    pub max_speed: Option<f32>,
+    pub radius: Option<f32>,
+}
+
+impl TypeEdit {
+    fn new(type_id: usize, type1: &ParticleType) -> Self {
+        Self {
+            type_id,
+            mass: type1.mass,
+            friction: type1.friction,
+            max_speed: type1.max_speed,
+            radius: type1.radius,
+        }
+    }
+
+    /// Ignored if the type no longer exists
+    pub fn apply(&self, types: &mut [ParticleType]) {
+        if let Some(type1) = types.get_mut(self.type_id) {
+            type1.mass = self.mass;
+            type1.friction = self.friction;
+            type1.max_speed = self.max_speed;
+            type1.radius = self.radius;
+        }
+    }
+}
+
 enum ButtonType {
     Rect,
     Circle,
@@ -90,12 +190,14 @@ impl Button {
             }
         }
 
+        // Shrink the label so that it still fits when there are many types
+        let font_size = (self.area.size.y * 0.4).min(20.);
         draw_text(
             &self.label,
             self.area.pos.x + self.area.size.x / 2.0
-                - measure_text(&self.label, None, 20, 1.0).width / 2.0,
+                - measure_text(&self.label, None, font_size as u16, 1.0).width / 2.0,
             self.area.pos.y + self.area.size.y / 2.0,
-            20.0,
+            font_size,
             BLACK,
         );
     }
@@ -111,7 +213,7 @@ struct ButtonGrid {
 }
 
 impl ButtonGrid {
-    pub fn new(area: Area, rows: usize, cols: usize, types: &Vec<ParticleType>) -> Self {
+    pub fn new(area: Area, rows: usize, cols: usize, types: &[ParticleType]) -> Self {
         let button_size = area.size.x / (cols + 1) as f32;
 
         let mut row_buttons = Vec::new();
@@ -170,42 +272,45 @@ impl ButtonGrid {
             cols,
         };
 
-        for i in 0..rows {
-            for j in 0..cols {
-                return_val.update_grid_button(i, j, types);
-            }
-        }
+        return_val.update_grid_buttons(types, Matrix::Attraction);
 
         return_val
     }
 
-    pub fn click(&mut self, point: Vec2, types: &mut [ParticleType], click_type: ClickType) {
-        // Ignore row and column buttons and if click was on a grid button, increase ParticleType attraction index
-        for row in 0..self.rows {
-            for col in 0..self.cols {
-                let button = &mut self.buttons[row * self.cols + col];
-                if button.area.contains(point) {
-                    let attr = &mut types[row].attraction[col];
-                    match click_type {
-                        ClickType::Left => {
-                            *attr = f32::min(*attr + CHANGE_TYPE_ATTRACTION_SPEED, 1.0);
-                        }
-                        ClickType::Right => {
-                            *attr = f32::max(*attr - CHANGE_TYPE_ATTRACTION_SPEED, -1.0);
-                        }
-                    }
-                }
-            }
-        }
+    /// Row whose row button contains `point`, if any
+    pub fn row_at(&self, point: Vec2) -> Option<usize> {
+        self.row_buttons
+            .iter()
+            .position(|button| button.area.contains(point))
     }
 
-    pub fn draw(&mut self, types: &[ParticleType]) {
+    /// Row, column and change of the entry whose button was clicked, if any.
+    /// Row and column buttons are ignored.
+    pub fn click(
+        &self,
+        point: Vec2,
+        click_type: ClickType,
+        change_speed: f32,
+    ) -> Option<(usize, usize, f32)> {
+        let index = self
+            .buttons
+            .iter()
+            .position(|button| button.area.contains(point))?;
+        let change = match click_type {
+            ClickType::Left => change_speed,
+            ClickType::Right => -change_speed,
+        };
+
+        Some((index / self.cols, index % self.cols, change))
+    }
+
+    pub fn draw(&mut self, types: &[ParticleType], matrix: Matrix, background_color: Color) {
         draw_rectangle(
             self.area.pos.x,
             self.area.pos.y,
             self.area.size.x,
             self.area.size.y,
-            MENU_BACKGORUND_COLOR,
+            background_color,
         );
 
         for button in &self.row_buttons {
@@ -216,44 +321,89 @@ impl ButtonGrid {
             button.draw();
         }
 
+        self.update_grid_buttons(types, matrix);
+
         for i in 0..self.rows {
             for j in 0..self.cols {
-                self.update_grid_button(i, j, types);
+                self.buttons[i * self.cols + j].draw();
             }
         }
+    }
 
+    fn update_grid_buttons(&mut self, types: &[ParticleType], matrix: Matrix) {
+        // Largest radius of any pair, the same for every button
+        let range = types
+            .iter()
+            .fold(0., |range, type1| type1.range().max(range));
         for i in 0..self.rows {
             for j in 0..self.cols {
-                self.buttons[i * self.cols + j].draw();
+                self.update_grid_button(i, j, types, matrix, range);
             }
         }
     }
 
-    fn update_grid_button(&mut self, row: usize, col: usize, types: &[ParticleType]) {
+    fn update_grid_button(
+        &mut self,
+        row: usize,
+        col: usize,
+        types: &[ParticleType],
+        matrix: Matrix,
+        range: f32,
+    ) {
         let button = &mut self.buttons[row * self.cols + col];
 
-        let attr = types[row].attraction[col];
-        button.label = format!("{:.2}", attr);
+        let value = matrix.row(&types[row])[col];
+        if matrix == Matrix::Attraction {
+            button.label = format!("{:.2}", value);
 
-        // Gradient that goes through white between green and red
-        button.color = if attr > 0.0 {
-            Color::new(1. - attr, 1., 1. - attr, 1.)
+            // Gradient that goes through white between green and red
+            button.color = if value > 0.0 {
+                Color::new(1. - value, 1., 1. - value, 1.)
+            } else {
+                Color::new(1., 1. + value, 1. + value, 1.)
+            };
         } else {
-            Color::new(1., 1. + attr, 1. + attr, 1.)
-        };
+            button.label = format!("{:.0}", value);
+
+            // From white to blue as the radius approaches the largest one of any pair
+            let blue = 0.8 * value / range;
+            button.color = Color::new(1. - blue, 1. - blue, 1., 1.);
+        }
     }
 }
 
 pub struct Menu {
     pub area: Area,
     attraction_grid: ButtonGrid,
+    matrix: Matrix,               // Shown in `attraction_grid`
+    selected_type: Option<usize>, // Type whose properties window is open
+    type_edit: Option<TypeEdit>,
+    config: MenuConfig,
+    presets_dir: PathBuf,
     show_help: bool,
+    presets: Vec<PathBuf>,
+    selected_preset: Option<PathBuf>,
 }
 
 impl Menu {
-    pub fn new(types: &Vec<ParticleType>) -> Self {
-        let area = Area::new([0.0, 0.0], [MENU_AREA_SIZE_PX.x, MENU_AREA_SIZE_PX.y]);
+    pub fn new(types: &[ParticleType], config: &Config) -> Self {
+        let area = Area::new([0.0, 0.0], [config.menu.width, config.window.size[1]]);
+
+        Menu {
+            area,
+            attraction_grid: Self::build_attraction_grid(area, types),
+            matrix: Matrix::Attraction,
+            selected_type: None,
+            type_edit: None,
+            config: config.menu.clone(),
+            presets_dir: config.files.presets_dir.clone(),
+            show_help: false,
+            presets: preset::list(&config.files.presets_dir),
+            selected_preset: None,
+        }
+    }
 
+    fn build_attraction_grid(area: Area, types: &[ParticleType]) -> ButtonGrid {
         let grid_x_size = area.size.x / 1.1;
         let grid_area = Area::new(
             [
@@ -263,39 +413,95 @@ impl Menu {
             [grid_x_size, grid_x_size],
         );
 
-        let mut attraction_grid = ButtonGrid::new(grid_area, COLORS.len(), COLORS.len(), &types);
+        let mut attraction_grid = ButtonGrid::new(grid_area, types.len(), types.len(), types);
 
-        for i in 0..COLORS.len() {
-            attraction_grid.row_buttons[i].color = COLORS[i];
+        for (button, type1) in attraction_grid.row_buttons.iter_mut().zip(types) {
+            button.color = Color::from(type1.color);
         }
 
-        for i in 0..COLORS.len() {
-            attraction_grid.column_buttons[i].color = COLORS[i];
+        for (button, type1) in attraction_grid.column_buttons.iter_mut().zip(types) {
+            button.color = Color::from(type1.color);
         }
 
-        Menu {
-            area,
-            attraction_grid,
-            show_help: false,
+        attraction_grid
+    }
+
+    /// Rebuilds the attraction grid if types were added, removed or recolored since the last call
+    pub fn sync_types(&mut self, types: &[ParticleType]) {
+        let colors = types.iter().map(|type1| Color::from(type1.color));
+        let row_colors = self.attraction_grid.row_buttons.iter().map(|b| b.color);
+        if types.len() != self.attraction_grid.rows || !colors.eq(row_colors) {
+            self.attraction_grid = Self::build_attraction_grid(self.area, types);
+        }
+        self.selected_type = self.selected_type.filter(|&type_id| type_id < types.len());
+    }
+
+    /// Opens the properties window of the type whose row button is at `point`, or closes it
+    /// if it was already open
+    pub fn select_type(&mut self, point: Vec2) {
+        if let Some(row) = self.attraction_grid.row_at(point) {
+            self.selected_type = if self.selected_type == Some(row) {
+                None
+            } else {
+                Some(row)
+            };
         }
     }
 
-    pub fn click(&mut self, point: Vec2, types: &mut Vec<ParticleType>, click_type: ClickType) {
-        self.attraction_grid.click(point, types, click_type);
+    /// Properties changed in the type window since the last call
+    pub fn take_type_edit(&mut self) -> Option<TypeEdit> {
+        self.type_edit.take()
+    }
+
+    /// Entry of the shown matrix that was clicked, see `ButtonGrid::click`
+    pub fn click(&self, point: Vec2, click_type: ClickType) -> Option<MatrixEdit> {
+        let change_speed = match self.matrix {
+            Matrix::Attraction => self.config.change_type_attraction_speed,
+            Matrix::MinDistance | Matrix::MaxDistance => self.config.change_radius_speed,
+        };
+        let (row, col, change) = self
+            .attraction_grid
+            .click(point, click_type, change_speed)?;
+
+        Some(MatrixEdit {
+            matrix: self.matrix,
+            row,
+            col,
+            change,
+        })
+    }
+
+    /// Picks up a reloaded config. The menu keeps its size until the next start.
+    pub fn set_config(&mut self, config: &Config) {
+        self.config = config.menu.clone();
+        if self.presets_dir != config.files.presets_dir {
+            self.presets_dir = config.files.presets_dir.clone();
+            self.refresh_presets();
+        }
     }
 
     pub fn toggle_help(&mut self) {
         self.show_help = !self.show_help;
     }
 
+    pub fn refresh_presets(&mut self) {
+        self.presets = preset::list(&self.presets_dir);
+    }
+
+    /// Preset clicked in the browser since the last call
+    pub fn take_selected_preset(&mut self) -> Option<PathBuf> {
+        self.selected_preset.take()
+    }
+
     // Draw so that it stays in the same place on the screen
-    pub fn draw(&mut self, types: &Vec<ParticleType>) {
+    // `params` starts as a copy of the frame's, changes from the sliders are written into it
+    pub fn draw(&mut self, frame: &Frame, params: &mut SimulationParams) {
         draw_rectangle(
             self.area.pos.x,
             self.area.pos.y,
             self.area.size.x,
             screen_height(),
-            MENU_BACKGORUND_COLOR,
+            Color::from(self.config.background_color),
         );
 
         use macroquad::ui;
@@ -309,7 +515,7 @@ impl Menu {
             area.pos.x + area.size.x / 2.0 - grid_x_size / 2.0,
             attraction_grid_bottom + area.size.x / 2.0 - grid_x_size / 2.0,
         );
-        let slider_window_size = vec2(grid_x_size, 70.);
+        let slider_window_size = vec2(grid_x_size, 120.);
         draw_rectangle(
             slider_window_pos.x,
             slider_window_pos.y,
@@ -321,15 +527,78 @@ impl Menu {
         root_ui().window(hash!(), slider_window_pos, slider_window_size, |ui| {
             ui::widgets::Slider::new(hash!(), 0.01..5.)
                 .label("Repel")
-                .ui(ui, unsafe { &mut REPEL_CONSTANT });
+                .ui(ui, &mut params.repel_constant);
             ui::widgets::Slider::new(hash!(), 0.001..0.1)
                 .label("Attract")
-                .ui(ui, unsafe { &mut ATTRACT_CONSTANT });
-            ui::widgets::Slider::new(hash!(), 0.0..1.)
+                .ui(ui, &mut params.attract_constant);
+            ui::widgets::Slider::new(hash!(), 0.0..30.)
                 .label("Friction")
-                .ui(ui, unsafe { &mut PARTICLE_FRICTION });
+                .ui(ui, &mut params.friction);
+
+            let names = ForceProfile::ALL.map(ForceProfile::name);
+            let mut profile = ForceProfile::ALL
+                .iter()
+                .position(|&profile| profile == params.force_profile)
+                .unwrap_or_default();
+            ui::widgets::ComboBox::new(hash!(), &names)
+                .label("Force")
+                .ui(ui, &mut profile);
+            params.force_profile = ForceProfile::ALL[profile];
+
+            let names = Matrix::ALL.map(Matrix::name);
+            let mut matrix = Matrix::ALL
+                .iter()
+                .position(|&matrix| matrix == self.matrix)
+                .unwrap_or_default();
+            ui::widgets::ComboBox::new(hash!(), &names)
+                .label("Matrix")
+                .ui(ui, &mut matrix);
+            self.matrix = Matrix::ALL[matrix];
         });
 
+        draw_text(
+            &format!("Seed: {}", frame.seed),
+            slider_window_pos.x,
+            slider_window_pos.y + slider_window_size.y + 30.,
+            30.,
+            WHITE,
+        );
+        // The simulation steps on its own thread, so it can fall behind while drawing keeps up
+        draw_text(
+            &format!(
+                "Simulation: {:.0} steps/s   Drawing: {} FPS",
+                frame.steps_per_second,
+                get_fps()
+            ),
+            slider_window_pos.x,
+            slider_window_pos.y + slider_window_size.y + 55.,
+            20.,
+            WHITE,
+        );
+
+        // Preset browser, one button per file in the presets directory
+        let preset_window_pos = slider_window_pos + vec2(0., slider_window_size.y + 70.);
+        let preset_window_size = vec2(grid_x_size, 120.);
+        let mut refresh = false;
+        root_ui().window(hash!(), preset_window_pos, preset_window_size, |ui| {
+            ui::widgets::Label::new(format!("Presets ({})", self.presets_dir.display())).ui(ui);
+            if ui::widgets::Button::new("Refresh").ui(ui) {
+                refresh = true;
+            }
+            for path in &self.presets {
+                let name = path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+                if ui::widgets::Button::new(name).ui(ui) {
+                    self.selected_preset = Some(path.clone());
+                }
+            }
+        });
+        if refresh {
+            self.refresh_presets();
+        }
+
         let text_size = 45.;
         draw_text(
             "Press H to toggle help",
@@ -339,18 +608,29 @@ impl Menu {
             WHITE,
         );
 
-        self.attraction_grid.draw(types);
+        self.attraction_grid.draw(
+            &frame.types,
+            self.matrix,
+            Color::from(self.config.background_color),
+        );
+
+        if let Some(type_id) = self.selected_type {
+            self.draw_type_window(frame, type_id);
+        }
 
         // Draw help window
         if self.show_help {
-            let size = vec2(400., 400.);
+            let size = vec2(400., 480.);
             let pos = vec2(
                 screen_width() / 2. - size.x / 2.,
                 screen_height() / 2. - size.y / 2.,
             );
             root_ui().window(hash!(), pos, size, |ui| {
-                ui::widgets::Label::new("Left click the matrix to increase attraction").ui(ui);
-                ui::widgets::Label::new("Right click the matrix to decrease attraction").ui(ui);
+                ui::widgets::Label::new("Left click the matrix to increase an entry").ui(ui);
+                ui::widgets::Label::new("Right click the matrix to decrease an entry").ui(ui);
+                ui::widgets::Label::new("Pick the matrix below the sliders").ui(ui);
+                ui::widgets::Label::new("Click a row's type to edit its mass, friction,").ui(ui);
+                ui::widgets::Label::new("max speed and radius").ui(ui);
                 ui::widgets::Label::new("Left click and drag to move around").ui(ui);
                 ui::widgets::Label::new("Scroll to zoom in and out").ui(ui);
                 ui::widgets::Label::new("").ui(ui);
@@ -358,9 +638,72 @@ impl Menu {
                 ui::widgets::Label::new("A     - randomize attraction").ui(ui);
                 ui::widgets::Label::new("C     - clear attraction").ui(ui);
                 ui::widgets::Label::new("N     - randomize particles and attraction").ui(ui);
+                ui::widgets::Label::new("S     - save snapshot").ui(ui);
+                ui::widgets::Label::new("L     - load snapshot").ui(ui);
+                ui::widgets::Label::new("E     - export preset").ui(ui);
+                ui::widgets::Label::new("V     - toggle grid and neighbour list search").ui(ui);
+                ui::widgets::Label::new("+     - add a particle type").ui(ui);
+                ui::widgets::Label::new("-     - remove the last particle type").ui(ui);
                 ui::widgets::Label::new("H     - toggle help").ui(ui);
                 ui::widgets::Label::new("Space - pause").ui(ui);
             });
         }
     }
+
+    // Sliders for the properties of one type, next to the menu. Unchecking a property falls
+    // back to the params.
+    fn draw_type_window(&mut self, frame: &Frame, type_id: usize) {
+        use macroquad::ui;
+
+        let type1 = &frame.types[type_id];
+        let mut edit = TypeEdit::new(type_id, type1);
+        let mut own_friction = edit.friction.is_some();
+        let mut friction = edit.friction.unwrap_or(frame.params.friction);
+        let mut limit_speed = edit.max_speed.is_some();
+        let mut max_speed = edit.max_speed.unwrap_or(500.);
+        let mut own_radius = edit.radius.is_some();
+        let mut radius = type1.radius(&frame.params);
+
+        let pos = vec2(
+            self.area.pos.x + self.area.size.x + 10.,
+            self.area.pos.y + 10.,
+        );
+        let size = vec2(320., 175.);
+        root_ui().window(hash!(), pos, size, |ui| {
+            ui::widgets::Label::new(format!("Type {}", type_id + 1)).ui(ui);
+            ui::widgets::Slider::new(hash!(), 0.1..10.)
+                .label("Mass")
+                .ui(ui, &mut edit.mass);
+            ui::widgets::Checkbox::new(hash!())
+                .label("Own friction")
+                .ui(ui, &mut own_friction);
+            ui::widgets::Slider::new(hash!(), 0.0..30.)
+                .label("Friction")
+                .ui(ui, &mut friction);
+            ui::widgets::Checkbox::new(hash!())
+                .label("Limit speed")
+                .ui(ui, &mut limit_speed);
+            ui::widgets::Slider::new(hash!(), 10.0..2000.)
+                .label("Max speed")
+                .ui(ui, &mut max_speed);
+            ui::widgets::Checkbox::new(hash!())
+                .label("Own radius")
+                .ui(ui, &mut own_radius);
+            ui::widgets::Slider::new(hash!(), 0.5..15.)
+                .label("Radius")
+                .ui(ui, &mut radius);
+        });
+        edit.mass = edit.mass.max(0.1);
+        edit.friction = own_friction.then_some(friction);
+        edit.max_speed = limit_speed.then_some(max_speed.max(1.));
+        edit.radius = own_radius.then_some(radius.max(0.5));
+
+        let changed = edit.mass != type1.mass
+            || edit.friction != type1.friction
+            || edit.max_speed != type1.max_speed
+            || edit.radius != type1.radius;
+        if changed {
+            self.type_edit = Some(edit);
+        }
+    }
 }
@@ -0,0 +1,161 @@
+//! Verlet neighbour lists: every particle remembers the particles within the interaction
+//! range plus a skin distance. The lists stay valid until some particle has moved more than
+//! half the skin, until then the grid doesn't have to be searched.
+
+use glam::Vec2;
+
+use crate::grid::Grid;
+use crate::kernel::select;
+use crate::pool::WorkerPool;
+
+#[derive(Default)]
+pub struct NeighbourList {
+    range: f32, // Interaction range plus skin the lists were built for
+    skin: f32,
+    start: Vec<usize>, // The neighbours of particle `i` are `neighbours[start[i]..start[i + 1]]`
+    neighbours: Vec<u32>,
+    built_at: Vec<Vec2>, // Positions when the lists were built
+    partial: Vec<PartialList>,
+}
+
+// Lists of the particles in a contiguous range of cells, concatenated afterwards
+#[derive(Default)]
+struct PartialList {
+    len: Vec<usize>,
+    neighbours: Vec<u32>,
+    distances: Vec<f32>, // Squared distances to the candidates of one range, reused
+}
+
+impl NeighbourList {
+    /// Whether the lists have to be rebuilt before they can be used for particles at `x`/`y`
+    /// with interaction range `range`. `wrap_size` is the world size along its periodic axes,
+    /// see `Boundary::wrap_size`.
+    pub fn is_stale(&self, x: &[f32], y: &[f32], wrap_size: Vec2, range: f32, skin: f32) -> bool {
+        if self.range != range + skin || self.skin != skin || self.built_at.len() != x.len() {
+            return true;
+        }
+
+        let max_distance_squared = (skin / 2.) * (skin / 2.);
+        self.built_at
+            .iter()
+            .zip(x.iter().zip(y))
+            .any(|(built_at, (&x, &y))| {
+                wrapped_offset(Vec2::new(x, y) - *built_at, wrap_size).length_squared()
+                    > max_distance_squared
+            })
+    }
+
+    /// Marks the lists as stale, e.g. after particles were added or reordered
+    pub fn invalidate(&mut self) {
+        self.built_at.clear();
+    }
+
+    /// Builds the lists of the particles at `x`/`y` from `grid`, whose cells have to be at
+    /// least `range + skin` wide. `sorted_x`/`sorted_y` are the same positions in grid order.
+    ///
+    /// Particles are numbered in grid order, see `Grid::particle_index`, both for the lists
+    /// and for the neighbours in them. Particles close to each other then scan the same cells
+    /// and read the same positions one after the other instead of jumping around in memory.
+    #[allow(clippy::too_many_arguments)]
+    pub fn build(
+        &mut self,
+        grid: &Grid,
+        x: &[f32],
+        y: &[f32],
+        sorted_x: &[f32],
+        sorted_y: &[f32],
+        wrap_size: Vec2,
+        range: f32,
+        skin: f32,
+        pool: &WorkerPool,
+    ) {
+        let list_range = range + skin;
+        let range_squared = list_range * list_range;
+
+        let num_cells = grid.num_cells();
+        let cell_start = grid.cell_start();
+        let tasks = pool.threads();
+        let cells_per_task = num_cells.div_ceil(tasks).max(1);
+        self.partial.resize_with(tasks, PartialList::default);
+        pool.for_each(self.partial.iter_mut().enumerate(), |(task, partial)| {
+            let first = (task * cells_per_task).min(num_cells);
+            let last = (first + cells_per_task).min(num_cells);
+            partial.len.clear();
+            partial.neighbours.clear();
+
+            for cell in first..last {
+                let ranges = grid.neighbour_ranges(cell);
+                for i in cell_start[cell]..cell_start[cell + 1] {
+                    let pos = Vec2::new(sorted_x[i], sorted_y[i]);
+                    let before = partial.neighbours.len();
+                    for range in ranges.iter().filter(|range| !range.is_empty()).cloned() {
+                        // All distances first, that loop is vectorised
+                        let distances = &mut partial.distances;
+                        distances.clear();
+                        distances.extend(
+                            sorted_x[range.clone()]
+                                .iter()
+                                .zip(&sorted_y[range.clone()])
+                                .map(|(&x, &y)| {
+                                    wrapped_offset(Vec2::new(x, y) - pos, wrap_size)
+                                        .length_squared()
+                                }),
+                        );
+                        if range.contains(&i) {
+                            distances[i - range.start] = f32::INFINITY;
+                        }
+
+                        // Every candidate is written, but only the ones in range are kept.
+                        // Branching on the distance would be mispredicted for about every
+                        // other candidate.
+                        let mut len = partial.neighbours.len();
+                        partial.neighbours.resize(len + range.len(), 0);
+                        for (j, &distance) in range.zip(&*distances) {
+                            partial.neighbours[len] = j as u32;
+                            len += usize::from(distance < range_squared);
+                        }
+                        partial.neighbours.truncate(len);
+                    }
+                    partial.len.push(partial.neighbours.len() - before);
+                }
+            }
+        });
+
+        self.start.clear();
+        self.start.push(0);
+        self.neighbours.clear();
+        for partial in &self.partial {
+            for &len in &partial.len {
+                self.start.push(self.start[self.start.len() - 1] + len);
+            }
+            self.neighbours.extend_from_slice(&partial.neighbours);
+        }
+
+        self.built_at.clear();
+        self.built_at
+            .extend(x.iter().zip(y).map(|(&x, &y)| Vec2::new(x, y)));
+        self.range = list_range;
+        self.skin = skin;
+    }
+
+    /// Running total of the list lengths, one entry per particle plus the total at the end
+    pub fn start(&self) -> &[usize] {
+        &self.start
+    }
+
+    pub fn neighbours(&self, i: usize) -> &[u32] {
+        &self.neighbours[self.start[i]..self.start[i + 1]]
+    }
+}
+
+// Takes the shortest way around the periodic edges, `wrap_size` is zero along the others
+#[inline(always)]
+fn wrapped_offset(mut offset: Vec2, wrap_size: Vec2) -> Vec2 {
+    let half = wrap_size / 2.;
+    offset.x -= select(offset.x > half.x, wrap_size.x, 0.);
+    offset.x += select(offset.x < -half.x, wrap_size.x, 0.);
+    offset.y -= select(offset.y > half.y, wrap_size.y, 0.);
+    offset.y += select(offset.y < -half.y, wrap_size.y, 0.);
+
+    offset
+}
@@ -0,0 +1,74 @@
+use particle_life::boundary::{Boundary, BoundaryMode};
+use particle_life::particle::{Particle, ParticleType};
+use particle_life::{Simulation, SimulationParams, Vec2};
+
+mod common;
+
+const WORLD_SIZE: Vec2 = Vec2::new(640., 480.);
+
+// Lone particles, too far apart to interact, moving without friction
+fn simulation(boundary: Boundary, particles: &[Particle]) -> Simulation {
+    let params = SimulationParams {
+        friction: 0.,
+        ..SimulationParams::default()
+    };
+    let types = vec![ParticleType::new([1.; 4], vec![0.])];
+    let mut simulation = common::with_particles(WORLD_SIZE, params, types, particles);
+    simulation.particles.set_boundary(boundary);
+    simulation
+}
+
+fn positions(simulation: &Simulation) -> Vec<Vec2> {
+    simulation.particles.iter().map(|p| p.pos).collect()
+}
+
+#[test]
+fn absorbing_one_axis_keeps_particles_at_the_other() {
+    let others = [
+        BoundaryMode::Periodic,
+        BoundaryMode::Reflect,
+        BoundaryMode::SoftWall,
+    ];
+    for other in others {
+        // Each leaves the world along the axis that does not absorb, by either edge
+        let leaving_x = [
+            Particle::new([0.5, 100.], [-600., 0.], 0),
+            Particle::new([639.5, 300.], [600., 0.], 0),
+        ];
+        let leaving_y = [
+            Particle::new([100., 0.5], [0., -600.], 0),
+            Particle::new([400., 479.9], [0., 600.], 0),
+        ];
+        for (boundary, particles) in [
+            (Boundary::new(other, BoundaryMode::Absorb), leaving_x),
+            (Boundary::new(BoundaryMode::Absorb, other), leaving_y),
+        ] {
+            let mut simulation = simulation(boundary, &particles);
+            simulation.step();
+            let positions = positions(&simulation);
+            assert_eq!(positions.len(), 2, "{:?} lost particles", boundary);
+            for pos in positions {
+                assert!(
+                    (0.0..WORLD_SIZE.x).contains(&pos.x) && (0.0..WORLD_SIZE.y).contains(&pos.y),
+                    "{:?} left {} outside of the world",
+                    boundary,
+                    pos
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn absorbing_axis_removes_particles() {
+    let boundary = Boundary::new(BoundaryMode::Absorb, BoundaryMode::Periodic);
+    let mut simulation = simulation(
+        boundary,
+        &[
+            Particle::new([0.5, 0.5], [-600., -600.], 0),
+            Particle::new([300., 240.], [0., 0.], 0),
+        ],
+    );
+    simulation.step();
+    assert_eq!(positions(&simulation), vec![Vec2::new(300., 240.)]);
+}
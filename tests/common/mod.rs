@@ -0,0 +1,60 @@
+//! Simulations shared by the integration tests. Every test binary compiles this module on its
+//! own and only uses part of it.
+#![allow(dead_code)]
+
+use particle_life::defaults::COLORS;
+use particle_life::particle::{Particle, ParticleType, Particles};
+use particle_life::{Simulation, SimulationParams, Vec2};
+
+/// Random types and particles, stepped on unpinned threads
+pub struct Builder {
+    pub world_size: Vec2,
+    pub num_particles: usize,
+    pub num_types: usize, // The first colors of `COLORS`
+    pub params: SimulationParams,
+    pub seed: u64,
+    pub threads: usize,
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Self {
+            world_size: Vec2::new(800., 800.),
+            num_particles: 1000,
+            num_types: COLORS.len(),
+            params: SimulationParams::default(),
+            seed: 0,
+            threads: 1,
+        }
+    }
+}
+
+impl Builder {
+    pub fn build(self) -> Simulation {
+        let mut simulation = Simulation::new(
+            self.world_size,
+            self.num_particles,
+            &COLORS[..self.num_types],
+            self.params,
+            self.seed,
+        );
+        simulation.set_threads(self.threads, false);
+        simulation
+    }
+}
+
+/// The given types and particles, stepped on one thread
+pub fn with_particles(
+    world_size: Vec2,
+    params: SimulationParams,
+    types: Vec<ParticleType>,
+    particles: &[Particle],
+) -> Simulation {
+    let mut all = Particles::new(world_size, &params);
+    for &particle in particles {
+        all.add_particle(particle);
+    }
+    let mut simulation = Simulation::from_parts(all, types, params, world_size, 0);
+    simulation.set_threads(1, false);
+    simulation
+}
@@ -0,0 +1,117 @@
+use std::fs::File;
+use std::io::BufWriter;
+
+use particle_life::domain::{Domain, DomainShape};
+use particle_life::{Simulation, Vec2};
+
+mod common;
+use common::Builder;
+
+const WORLD_SIZE: Vec2 = Vec2::new(1600., 1200.);
+
+fn simulation(domain: &Domain) -> Simulation {
+    let mut simulation = Builder {
+        world_size: WORLD_SIZE,
+        num_particles: 3000,
+        seed: 11,
+        threads: 2,
+        ..Builder::default()
+    }
+    .build();
+    simulation.set_domain(domain.clone()).unwrap();
+    simulation
+}
+
+fn assert_confined(domain: &Domain) {
+    let mut simulation = simulation(domain);
+    for step in 0..100 {
+        for particle in simulation.particles.iter() {
+            assert!(
+                domain.contains(particle.pos, WORLD_SIZE),
+                "{:?} is outside of the domain after {} steps",
+                particle.pos,
+                step
+            );
+        }
+        simulation.step();
+    }
+}
+
+#[test]
+fn disk_and_annulus_confine_particles() {
+    assert_confined(&Domain::load(&DomainShape::Disk { radius: 500. }).unwrap());
+    assert_confined(
+        &Domain::load(&DomainShape::Annulus {
+            inner_radius: 200.,
+            outer_radius: 550.,
+        })
+        .unwrap(),
+    );
+}
+
+// Writes a grayscale PNG to a file of its own, so that tests running at the same time don't
+// collide, and loads it as a mask
+fn mask(name: &str, width: usize, height: usize, white: impl Fn(usize, usize) -> bool) -> Domain {
+    let pixels: Vec<u8> = (0..width * height)
+        .map(|i| if white(i % width, i / width) { 255 } else { 0 })
+        .collect();
+    let path =
+        std::env::temp_dir().join(format!("particle-life-{}-{}.png", std::process::id(), name));
+    let mut encoder = png::Encoder::new(
+        BufWriter::new(File::create(&path).unwrap()),
+        width as u32,
+        height as u32,
+    );
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder
+        .write_header()
+        .unwrap()
+        .write_image_data(&pixels)
+        .unwrap();
+
+    let domain = Domain::load(&DomainShape::Mask { path: path.clone() });
+    std::fs::remove_file(&path).unwrap();
+    domain.unwrap()
+}
+
+#[test]
+fn mask_confines_particles() {
+    // A white cross on black, 8 by 6 pixels
+    let domain = mask("cross", 8, 6, |x, y| {
+        (3..5).contains(&x) || (2..4).contains(&y)
+    });
+    assert!(domain.contains(Vec2::new(800., 600.), WORLD_SIZE));
+    assert!(!domain.contains(Vec2::new(100., 100.), WORLD_SIZE));
+    assert_confined(&domain);
+}
+
+#[test]
+fn tiny_domains_place_particles() {
+    // Rejection sampling over the whole world would need millions of tries per particle
+    let disk = Domain::load(&DomainShape::Disk { radius: 0.5 }).unwrap();
+    let dot = mask("dot", 1000, 1000, |x, y| (x, y) == (700, 200));
+    let ring = Domain::load(&DomainShape::Annulus {
+        inner_radius: 599.9,
+        outer_radius: 600.,
+    })
+    .unwrap();
+    for domain in [disk, dot, ring] {
+        let simulation = simulation(&domain);
+        assert!(simulation
+            .particles
+            .iter()
+            .all(|particle| domain.contains(particle.pos, WORLD_SIZE)));
+    }
+}
+
+#[test]
+fn domains_that_do_not_fit_are_rejected() {
+    let mut simulation = simulation(&Domain::Rect);
+    let annulus = Domain::load(&DomainShape::Annulus {
+        inner_radius: 700.,
+        outer_radius: 900.,
+    })
+    .unwrap();
+    assert!(simulation.set_domain(annulus).is_err());
+    assert!(simulation.particles.domain().is_rect());
+}
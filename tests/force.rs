@@ -0,0 +1,73 @@
+use particle_life::force::{Cosine, ForceLaw, InversePower, LennardJones, Triangle};
+use particle_life::SimulationParams;
+
+// Distances strictly inside the repulsive and the attractive range
+fn distances(params: &SimulationParams) -> (Vec<f32>, Vec<f32>) {
+    let (min, max) = (params.min_distance, params.max_distance);
+    let repel = (0..10).map(|i| min * i as f32 / 10.).collect();
+    let attract = (1..20)
+        .map(|i| min + (max - min) * i as f32 / 20.)
+        .collect();
+    (repel, attract)
+}
+
+fn check<F: ForceLaw>(name: &str) {
+    let params = SimulationParams::default();
+    let law = F::new(&params, params.min_distance, params.max_distance);
+    let (repel, attract) = distances(&params);
+
+    for distance in repel {
+        for attraction in [-1., 0., 1.] {
+            let magnitude = law.magnitude(distance, attraction);
+            assert!(
+                magnitude < 0. && magnitude >= -params.repel_constant,
+                "{} at {} with attraction {}: {}",
+                name,
+                distance,
+                attraction,
+                magnitude
+            );
+        }
+    }
+    for distance in attract {
+        let magnitude = law.magnitude(distance, 1.);
+        assert!(
+            magnitude > 0. && magnitude <= params.attract_constant * 1.0001,
+            "{} at {}: {}",
+            name,
+            distance,
+            magnitude
+        );
+        assert_eq!(law.magnitude(distance, -1.), -magnitude, "{}", name);
+        assert_eq!(law.magnitude(distance, 0.), 0., "{}", name);
+    }
+
+    // Both directions of a pair at once give the same forces as one at a time
+    let (repel, attract) = distances(&params);
+    for distance in repel.into_iter().chain(attract) {
+        assert_eq!(
+            law.magnitudes(distance, 0.7, -0.3),
+            (law.magnitude(distance, 0.7), law.magnitude(distance, -0.3)),
+            "{} at {}",
+            name,
+            distance
+        );
+    }
+}
+
+#[test]
+fn laws_repel_close_and_follow_attraction_further_out() {
+    check::<Triangle>("triangle");
+    check::<Cosine>("cosine");
+    check::<LennardJones>("lennard-jones");
+    check::<InversePower>("inverse power");
+}
+
+#[test]
+fn triangle_peaks_halfway() {
+    let params = SimulationParams::default();
+    let law = Triangle::new(&params, params.min_distance, params.max_distance);
+    let mid = (params.min_distance + params.max_distance) / 2.;
+    assert!((law.magnitude(mid, 1.) - params.attract_constant).abs() < 1e-6);
+    assert!((law.magnitude(0., 1.) + params.repel_constant).abs() < 1e-6);
+}
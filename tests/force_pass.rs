@@ -0,0 +1,35 @@
+use particle_life::{Simulation, Vec2};
+
+mod common;
+use common::Builder;
+
+fn simulation(threads: usize) -> Simulation {
+    Builder {
+        world_size: Vec2::new(1200., 1200.),
+        num_particles: 3000,
+        seed: 42,
+        threads,
+        ..Builder::default()
+    }
+    .build()
+}
+
+#[test]
+fn parallel_matches_serial() {
+    let mut serial = simulation(1);
+    let mut parallel = simulation(4);
+
+    for _ in 0..20 {
+        serial.step();
+        parallel.step();
+    }
+
+    let serial = &serial.particles;
+    let parallel = &parallel.particles;
+    assert_eq!(serial.len(), parallel.len());
+    for (a, b) in serial.iter().zip(parallel.iter()) {
+        assert_eq!(a.pos, b.pos);
+        assert_eq!(a.vel, b.vel);
+        assert_eq!(a.type_id, b.type_id);
+    }
+}
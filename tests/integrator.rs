@@ -0,0 +1,193 @@
+use particle_life::particle::{Particle, ParticleType};
+use particle_life::{Integrator, Simulation, SimulationParams, Vec2};
+
+mod common;
+
+const DURATION: f32 = 1.;
+const REFERENCE_DT: f32 = 1. / 1920.;
+
+// A few particles close enough to interact, far from the edges of the world
+fn simulation(integrator: Integrator, dt: f32) -> Simulation {
+    let params = SimulationParams {
+        dt,
+        integrator,
+        ..SimulationParams::default()
+    };
+    let world_size = Vec2::new(640., 640.);
+    let types = vec![
+        ParticleType::new([1.; 4], vec![0.8, -0.5]),
+        ParticleType::new([1.; 4], vec![0.6, 0.3]),
+    ];
+
+    let positions = [
+        [480., 500.],
+        [530., 490.],
+        [505., 545.],
+        [460., 450.],
+        [550., 530.],
+    ];
+    let particles: Vec<Particle> = positions
+        .into_iter()
+        .enumerate()
+        .map(|(i, pos)| Particle::new(pos, [0., 0.], i % 2))
+        .collect();
+    common::with_particles(world_size, params, types, &particles)
+}
+
+// Largest distance of a particle from where it is with the reference time step
+fn error(integrator: Integrator, dt: f32, reference: &[Vec2]) -> f32 {
+    let mut simulation = simulation(integrator, dt);
+    for _ in 0..(DURATION / dt).round() as usize {
+        simulation.step();
+    }
+    simulation
+        .particles
+        .iter()
+        .zip(reference)
+        .map(|(particle, pos)| (particle.pos - *pos).length())
+        .fold(0., f32::max)
+}
+
+// Errors with 60, 120 and 240 steps per second against the reference time step
+fn errors(integrator: Integrator) -> Vec<f32> {
+    let mut reference = simulation(integrator, REFERENCE_DT);
+    for _ in 0..(DURATION / REFERENCE_DT).round() as usize {
+        reference.step();
+    }
+    let reference: Vec<Vec2> = reference.particles.iter().map(|p| p.pos).collect();
+
+    [60., 120., 240.]
+        .iter()
+        .map(|steps_per_second| error(integrator, 1. / steps_per_second, &reference))
+        .collect()
+}
+
+#[test]
+fn integrators_converge() {
+    for integrator in [
+        Integrator::ExplicitEuler,
+        Integrator::SemiImplicitEuler,
+        Integrator::VelocityVerlet,
+    ] {
+        let errors = errors(integrator);
+        for pair in errors.windows(2) {
+            assert!(
+                pair[1] < pair[0] / 1.5,
+                "{:?} does not converge: {:?}",
+                integrator,
+                errors
+            );
+        }
+    }
+}
+
+#[test]
+fn velocity_verlet_is_more_accurate() {
+    let euler = errors(Integrator::SemiImplicitEuler);
+    let verlet = errors(Integrator::VelocityVerlet);
+    assert!(
+        verlet[0] < euler[0] / 4.,
+        "semi-implicit Euler: {:?}, velocity Verlet: {:?}",
+        euler,
+        verlet
+    );
+}
+
+// Types 0 and 1 with the given properties, one particle each at the given positions
+fn two_types(type0: ParticleType, type1: ParticleType, particles: [Particle; 2]) -> Simulation {
+    let params = SimulationParams::default();
+    common::with_particles(
+        Vec2::new(640., 640.),
+        params,
+        vec![type0, type1],
+        &particles,
+    )
+}
+
+#[test]
+fn heavier_types_accelerate_slower() {
+    // Closer than `min_distance`, so both repel each other equally
+    let light = ParticleType {
+        friction: Some(0.),
+        ..ParticleType::new([1.; 4], vec![0., 0.])
+    };
+    let heavy = ParticleType {
+        mass: 4.,
+        ..light.clone()
+    };
+    let mut simulation = two_types(
+        light,
+        heavy,
+        [
+            Particle::new([300., 320.], [0., 0.], 0),
+            Particle::new([305., 320.], [0., 0.], 1),
+        ],
+    );
+    simulation.step();
+
+    let light = simulation.particles.get(0).vel;
+    let heavy = simulation.particles.get(1).vel;
+    assert!(light.x < 0. && heavy.x > 0.);
+    assert!(
+        (light.length() / heavy.length() - 4.).abs() < 1e-3,
+        "light: {}, heavy: {}",
+        light,
+        heavy
+    );
+}
+
+#[test]
+fn types_have_their_own_friction_and_max_speed() {
+    // Far apart, so only friction and the speed limit change the velocities
+    let own_friction = ParticleType {
+        friction: Some(0.),
+        ..ParticleType::new([1.; 4], vec![0., 0.])
+    };
+    let limited = ParticleType {
+        max_speed: Some(100.),
+        ..ParticleType::new([1.; 4], vec![0., 0.])
+    };
+    let mut simulation = two_types(
+        own_friction,
+        limited,
+        [
+            Particle::new([100., 100.], [300., 400.], 0),
+            Particle::new([400., 400.], [-40., 30.], 1),
+        ],
+    );
+    let decay = (-simulation.params.friction * simulation.params.dt).exp();
+
+    simulation.step();
+    assert_eq!(simulation.particles.get(0).vel, Vec2::new(300., 400.));
+    let limited = simulation.particles.get(1).vel;
+    assert!((limited - Vec2::new(-40., 30.) * decay).length() < 1e-3);
+
+    // However fast it was, every integrator moves it by the max speed
+    for integrator in [
+        Integrator::ExplicitEuler,
+        Integrator::SemiImplicitEuler,
+        Integrator::VelocityVerlet,
+    ] {
+        simulation.params.integrator = integrator;
+        let start = Vec2::new(400., 400.);
+        simulation
+            .particles
+            .set(1, Particle::new(start.to_array(), [600., 800.], 1));
+        simulation.step();
+
+        let limited = simulation.particles.get(1);
+        let moved = (limited.pos - start).length() / simulation.params.dt;
+        assert!(
+            (moved - 100.).abs() < 0.01,
+            "{:?} moved at {}",
+            integrator,
+            moved
+        );
+        assert!(
+            limited.vel.length() <= 100.001,
+            "{:?}: {}",
+            integrator,
+            limited.vel
+        );
+    }
+}
@@ -0,0 +1,29 @@
+use particle_life::defaults::COLORS;
+use particle_life::pool::WorkerPool;
+use particle_life::{Simulation, Vec2};
+
+#[test]
+fn pinned_reports_what_happened() {
+    // One worker more than there are cores can't all get a core of their own
+    let cores = core_affinity::get_core_ids().unwrap_or_default().len();
+    let pool = WorkerPool::new(cores + 1, true);
+    assert!(pool.pin());
+    assert!(!pool.pinned());
+
+    let pool = WorkerPool::new(2, false);
+    assert!(!pool.pin());
+    assert!(!pool.pinned());
+}
+
+#[test]
+fn simulations_start_unpinned_pools() {
+    // Not built with the shared builder, which sets the threads
+    let mut simulation = Simulation::new(Vec2::new(400., 400.), 50, &COLORS, Default::default(), 1);
+    simulation.step();
+    assert!(!simulation.pinned());
+    assert_eq!(simulation.threads_error(), None);
+
+    simulation.set_threads(2, false);
+    assert_eq!(simulation.threads(), 2);
+    assert_eq!(simulation.threads_error(), None);
+}
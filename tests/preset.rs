@@ -0,0 +1,132 @@
+use std::path::PathBuf;
+
+use particle_life::defaults::{DT, REFERENCE_STEP};
+use particle_life::preset::{self, Preset, PresetError};
+use particle_life::{SimulationParams, Vec2};
+
+mod common;
+use common::Builder;
+
+// A file of its own in the temp dir, so that tests running at the same time don't collide
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("particle-life-{}-{}", std::process::id(), name))
+}
+
+fn load_text(name: &str, text: &str) -> Result<Preset, PresetError> {
+    let path = temp_path(name);
+    std::fs::write(&path, text).unwrap();
+    let preset = Preset::load(&path);
+    std::fs::remove_file(&path).unwrap();
+    preset
+}
+
+// Params as written before time steps were configurable
+fn old_preset(friction: f32) -> String {
+    format!(
+        "[params]
+min_distance = 10.0
+max_distance = 80.0
+repel_constant = 2.0
+attract_constant = 0.05
+friction = {}
+particle_radius = 3.0
+
+[[types]]
+color = [1.0, 0.0, 0.0, 1.0]
+attraction = [0.5]
+",
+        friction
+    )
+}
+
+#[test]
+fn presets_without_dt_have_per_frame_friction() {
+    let preset = load_text("old-friction.toml", &old_preset(0.15)).unwrap();
+    assert_eq!(preset.params.dt, DT);
+    // A velocity keeps 85% over one frame, like it did before
+    let kept = (-preset.params.friction * REFERENCE_STEP).exp();
+    assert!((kept - 0.85).abs() < 1e-5, "{}", kept);
+
+    let result = load_text("old-friction-too-high.toml", &old_preset(9.75));
+    assert!(matches!(result, Err(PresetError::Parse(_))), "{:?}", result);
+}
+
+#[test]
+fn shipped_presets_load() {
+    let presets = preset::list(concat!(env!("CARGO_MANIFEST_DIR"), "/presets"));
+    assert!(!presets.is_empty());
+    for path in presets {
+        let preset = Preset::load(&path);
+        assert!(preset.is_ok(), "{}: {:?}", path.display(), preset.err());
+    }
+}
+
+fn preset() -> Preset {
+    let params = SimulationParams {
+        friction: 0.16,
+        ..SimulationParams::default()
+    };
+    let mut simulation = Builder {
+        world_size: Vec2::new(400., 400.),
+        num_particles: 0,
+        num_types: 3,
+        params,
+        seed: 5,
+        ..Builder::default()
+    }
+    .build();
+    simulation.types[0].attraction[1] = 0.16;
+    simulation.types[2].mass = 0.3;
+    simulation.types[1].max_speed = Some(120.5);
+    Preset::from_simulation(&simulation)
+}
+
+#[test]
+fn presets_survive_saving_and_loading() {
+    for extension in ["toml", "json"] {
+        let original = preset();
+        let path = temp_path(&format!("round-trip.{}", extension));
+        original.save(&path).unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        let loaded = Preset::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        let loaded = loaded.unwrap();
+
+        // Floats are written as the f32 they are, not widened to f64
+        assert!(text.contains("0.16"), "{}", text);
+        assert!(!text.contains("0.1599"), "{}", text);
+        assert_eq!(loaded.params, original.params);
+        for (loaded, original) in loaded.types.iter().zip(&original.types) {
+            assert_eq!(loaded.color, original.color);
+            assert_eq!(loaded.attraction, original.attraction);
+            assert_eq!(loaded.min_distance, original.min_distance);
+            assert_eq!(loaded.max_distance, original.max_distance);
+            assert_eq!(loaded.mass, original.mass);
+            assert_eq!(loaded.max_speed, original.max_speed);
+        }
+    }
+}
+
+#[test]
+fn invalid_presets_are_rejected() {
+    let mut wrong_size = preset();
+    wrong_size.types[1].attraction.pop();
+    let mut out_of_range = preset();
+    out_of_range.types[2].attraction[0] = 1.5;
+    for (name, preset) in [("wrong-size", wrong_size), ("out-of-range", out_of_range)] {
+        assert!(
+            matches!(preset.validate(), Err(PresetError::Invalid(_))),
+            "{}",
+            name
+        );
+        // Saving doesn't check, loading does
+        let path = temp_path(&format!("{}.toml", name));
+        preset.save(&path).unwrap();
+        let result = Preset::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(PresetError::Invalid(_))), "{}", name);
+    }
+
+    let result = load_text("unknown.yaml", "params: {}");
+    assert!(matches!(result, Err(PresetError::UnknownFormat(_))));
+}
@@ -0,0 +1,181 @@
+use particle_life::boundary::{Boundary, BoundaryMode};
+use particle_life::defaults::{GAME_AREA_SIZE_U, NUM_PARTICLES};
+use particle_life::particle::{ForceKernel, NeighbourSearch};
+use particle_life::{ForceProfile, Simulation, Vec2};
+
+mod common;
+use common::Builder;
+
+const STEPS: usize = 3;
+// Only rounding differs between the searches, a missed neighbour shows up as a far larger
+// difference. Velocities are per second, so this is 1e-3 per 1/60 s.
+const MAX_DIVERGENCE: f32 = 0.06;
+
+// Same density as the default world, denser worlds amplify rounding differences too quickly
+fn simulation(world_size: Vec2) -> Simulation {
+    let density = NUM_PARTICLES as f32 / GAME_AREA_SIZE_U.x / GAME_AREA_SIZE_U.y;
+    Builder {
+        world_size,
+        num_particles: (world_size.x * world_size.y * density) as usize,
+        seed: 7,
+        threads: 2,
+        ..Builder::default()
+    }
+    .build()
+}
+
+fn assert_matches_reference(simulation: &Simulation) {
+    let divergence = simulation.reference_divergence(STEPS);
+    assert!(
+        divergence.iter().all(|&d| d < MAX_DIVERGENCE),
+        "velocities diverged from the reference: {:?}",
+        divergence
+    );
+}
+
+#[test]
+fn grid_matches_reference() {
+    for world_size in [Vec2::new(800., 800.), Vec2::new(987., 987.)] {
+        for kernel in [ForceKernel::Scalar, ForceKernel::Simd] {
+            let mut simulation = simulation(world_size);
+            simulation.particles.set_kernel(kernel);
+            assert_matches_reference(&simulation);
+        }
+    }
+}
+
+#[test]
+fn force_profiles_match_reference() {
+    for profile in ForceProfile::ALL {
+        let mut simulation = simulation(Vec2::new(800., 800.));
+        simulation.params.force_profile = profile;
+        for kernel in [ForceKernel::Scalar, ForceKernel::Simd] {
+            simulation.particles.set_kernel(kernel);
+            assert_matches_reference(&simulation);
+        }
+        simulation.particles.set_half_stencil(true);
+        assert_matches_reference(&simulation);
+    }
+}
+
+#[test]
+fn pair_radii_match_reference() {
+    // Asymmetric radii, some pairs reaching further than the params' max_distance
+    let mut simulation = simulation(Vec2::new(987., 987.));
+    for (i, type1) in simulation.types.iter_mut().enumerate() {
+        for j in 0..type1.attraction.len() {
+            type1.min_distance[j] = 5. + ((i * 3 + j) % 4) as f32 * 5.;
+            type1.max_distance[j] = 40. + ((i + j * 5) % 6) as f32 * 20.;
+        }
+    }
+    for kernel in [ForceKernel::Scalar, ForceKernel::Simd] {
+        simulation.particles.set_kernel(kernel);
+        assert_matches_reference(&simulation);
+    }
+    simulation.particles.set_half_stencil(true);
+    assert_matches_reference(&simulation);
+    simulation
+        .particles
+        .set_neighbour_search(NeighbourSearch::Verlet);
+    assert_matches_reference(&simulation);
+}
+
+#[test]
+fn half_stencil_matches_reference() {
+    let mut simulation = simulation(Vec2::new(987., 987.));
+    simulation.particles.set_half_stencil(true);
+    assert_matches_reference(&simulation);
+}
+
+#[test]
+fn verlet_matches_reference() {
+    let mut simulation = simulation(Vec2::new(987., 987.));
+    simulation
+        .particles
+        .set_neighbour_search(NeighbourSearch::Verlet);
+    assert_matches_reference(&simulation);
+}
+
+#[test]
+fn rectangular_worlds_match_reference() {
+    // Neither side is a multiple of the interaction range
+    for world_size in [Vec2::new(1300., 500.), Vec2::new(450., 1250.)] {
+        let mut simulation = simulation(world_size);
+        assert_matches_reference(&simulation);
+        simulation.particles.set_half_stencil(true);
+        assert_matches_reference(&simulation);
+        simulation
+            .particles
+            .set_neighbour_search(NeighbourSearch::Verlet);
+        assert_matches_reference(&simulation);
+    }
+}
+
+#[test]
+fn tiny_worlds_match_reference() {
+    // Fewer than three cells across, so the stencil wraps onto the same cells
+    for world_size in [
+        Vec2::new(250., 250.),
+        Vec2::new(1200., 170.),
+        Vec2::new(60., 900.),
+    ] {
+        let mut simulation = simulation(world_size);
+        assert_matches_reference(&simulation);
+        simulation
+            .particles
+            .set_neighbour_search(NeighbourSearch::Verlet);
+        assert_matches_reference(&simulation);
+    }
+}
+
+#[test]
+fn boundaries_match_reference() {
+    let modes = [
+        BoundaryMode::Reflect,
+        BoundaryMode::Absorb,
+        BoundaryMode::SoftWall,
+    ];
+    for mode in modes {
+        for boundary in [
+            Boundary::uniform(mode),
+            Boundary::new(BoundaryMode::Periodic, mode),
+            Boundary::new(mode, BoundaryMode::Periodic),
+        ] {
+            let mut simulation = simulation(Vec2::new(900., 700.));
+            simulation.particles.set_boundary(boundary);
+            assert_matches_reference(&simulation);
+            simulation.particles.set_half_stencil(true);
+            assert_matches_reference(&simulation);
+            simulation
+                .particles
+                .set_neighbour_search(NeighbourSearch::Verlet);
+            assert_matches_reference(&simulation);
+        }
+    }
+}
+
+#[test]
+fn switching_back_to_verlet_rebuilds_lists() {
+    // The grid search rebuilds the grid the lists were built from
+    let mut switched = simulation(Vec2::new(987., 987.));
+    let mut grid = simulation(Vec2::new(987., 987.));
+    // Reordering depends on the search, keep the particles comparable
+    switched.particles.set_reorder_interval(0);
+    grid.particles.set_reorder_interval(0);
+    for search in [
+        NeighbourSearch::Verlet,
+        NeighbourSearch::Grid,
+        NeighbourSearch::Verlet,
+    ] {
+        switched.particles.set_neighbour_search(search);
+        switched.step();
+        grid.step();
+    }
+    for (a, b) in switched.particles.iter().zip(grid.particles.iter()) {
+        let divergence = (a.vel - b.vel).abs().max_element();
+        assert!(
+            divergence < MAX_DIVERGENCE,
+            "velocities diverged by {divergence}"
+        );
+    }
+}
@@ -0,0 +1,101 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use particle_life::runner::{Frame, Rate, Runner};
+use particle_life::{Simulation, Vec2};
+
+mod common;
+use common::Builder;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+fn runner(steps_per_second: f32) -> Runner {
+    let simulation = Builder {
+        world_size: Vec2::new(500., 500.),
+        num_particles: 300,
+        num_types: 3,
+        seed: 3,
+        ..Builder::default()
+    }
+    .build();
+    let rate = Rate {
+        steps_per_second,
+        substeps: 2,
+    };
+    Runner::spawn(simulation, rate)
+}
+
+// Waits for a frame that satisfies `done`
+fn wait_for(runner: &mut Runner, done: impl Fn(&Frame) -> bool) -> Frame {
+    let start = Instant::now();
+    loop {
+        let frame = runner.frame();
+        if done(frame) {
+            return frame.clone();
+        }
+        assert!(start.elapsed() < TIMEOUT, "no matching frame was published");
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+#[test]
+fn frames_show_the_simulation() {
+    let mut runner = runner(1000.);
+    let frame = wait_for(&mut runner, |frame| frame.steps >= 5);
+    assert_eq!(frame.positions.len(), 300);
+    assert_eq!(frame.type_ids.len(), 300);
+    assert_eq!(frame.types.len(), 3);
+    assert_eq!(frame.seed, 3);
+
+    runner.edit(Simulation::clear_types);
+    let cleared = runner.read(|simulation| {
+        simulation
+            .types
+            .iter()
+            .all(|type1| type1.attraction.iter().all(|&attr| attr == 0.))
+    });
+    assert!(cleared);
+
+    let simulation = runner.stop();
+    assert_eq!(simulation.particles.len(), 300);
+}
+
+#[test]
+fn paused_runner_applies_edits_without_stepping() {
+    let mut runner = runner(1000.);
+    runner.set_paused(true);
+    let paused = runner.read(|simulation| simulation.particles.get(0).pos);
+    let steps = wait_for(&mut runner, |frame| frame.positions[0] == paused).steps;
+
+    runner.edit(|simulation| {
+        let mut particle = simulation.particles.get(0);
+        particle.pos = Vec2::new(1., 2.);
+        simulation.particles.set(0, particle);
+    });
+    let frame = wait_for(&mut runner, |frame| frame.positions[0] == Vec2::new(1., 2.));
+    assert_eq!(frame.steps, steps);
+    assert_eq!(frame.steps_per_second, 0.);
+
+    runner.set_paused(false);
+    wait_for(&mut runner, |frame| frame.steps > steps);
+}
+
+#[test]
+fn rates_without_steps_pause_the_runner() {
+    for steps_per_second in [0., -60., 1e-30, f32::NAN] {
+        let mut runner = runner(steps_per_second);
+        runner.edit(|simulation| {
+            let mut particle = simulation.particles.get(0);
+            particle.pos = Vec2::new(1., 2.);
+            simulation.particles.set(0, particle);
+        });
+        let frame = wait_for(&mut runner, |frame| frame.positions[0] == Vec2::new(1., 2.));
+        assert_eq!(frame.steps, 0, "{} steps per second", steps_per_second);
+
+        runner.set_rate(Rate {
+            steps_per_second: 1000.,
+            substeps: 1,
+        });
+        wait_for(&mut runner, |frame| frame.steps > 0);
+    }
+}
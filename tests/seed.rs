@@ -0,0 +1,50 @@
+use particle_life::particle::Particle;
+use particle_life::Simulation;
+
+mod common;
+use common::Builder;
+
+const STEPS: usize = 10;
+
+fn simulation(seed: u64) -> Simulation {
+    Builder {
+        seed,
+        threads: 2,
+        ..Builder::default()
+    }
+    .build()
+}
+
+fn particles(simulation: &Simulation) -> Vec<Particle> {
+    simulation.particles.iter().collect()
+}
+
+#[test]
+fn equal_seeds_give_equal_runs() {
+    let mut first = simulation(11);
+    let mut second = simulation(11);
+    for _ in 0..STEPS {
+        first.step();
+        second.step();
+    }
+    assert_eq!(particles(&first), particles(&second));
+}
+
+#[test]
+fn reseeding_matches_a_new_simulation() {
+    let mut reseeded = simulation(11);
+    for _ in 0..STEPS {
+        reseeded.step();
+    }
+    reseeded.reseed(5);
+    let mut fresh = simulation(5);
+
+    assert_eq!(reseeded.seed(), 5);
+    assert_eq!(reseeded.types, fresh.types);
+    assert_eq!(particles(&reseeded), particles(&fresh));
+    for _ in 0..STEPS {
+        reseeded.step();
+        fresh.step();
+    }
+    assert_eq!(particles(&reseeded), particles(&fresh));
+}
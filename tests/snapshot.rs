@@ -0,0 +1,119 @@
+use particle_life::boundary::{Boundary, BoundaryMode};
+use particle_life::domain::{Domain, DomainShape};
+use particle_life::snapshot::{self, SnapshotError};
+use particle_life::{Integrator, Simulation, SimulationParams, Vec2};
+
+mod common;
+use common::Builder;
+
+fn simulation() -> Simulation {
+    let params = SimulationParams {
+        integrator: Integrator::VelocityVerlet,
+        ..SimulationParams::default()
+    };
+    let mut simulation = Builder {
+        world_size: Vec2::new(900., 700.),
+        num_particles: 800,
+        num_types: 4,
+        params,
+        seed: 3,
+        ..Builder::default()
+    }
+    .build();
+    simulation.particles.set_reorder_interval(0);
+    simulation
+        .particles
+        .set_boundary(Boundary::new(BoundaryMode::Reflect, BoundaryMode::Periodic));
+    let disk = Domain::load(&DomainShape::Disk { radius: 330. }).unwrap();
+    simulation.set_domain(disk).unwrap();
+    simulation.types[1].mass = 2.5;
+    simulation.types[2].max_speed = Some(80.);
+    simulation.types[3].max_distance[0] = 120.;
+    for _ in 0..5 {
+        simulation.step();
+    }
+    simulation
+}
+
+fn to_bytes(simulation: &Simulation) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    snapshot::write(simulation, &mut bytes).unwrap();
+    bytes
+}
+
+#[test]
+fn snapshots_restore_the_simulation() {
+    let mut original = simulation();
+    let mut restored = snapshot::read(&mut to_bytes(&original).as_slice()).unwrap();
+    restored.set_threads(1, false);
+    restored.particles.set_reorder_interval(0);
+
+    assert_eq!(restored.seed(), original.seed());
+    assert_eq!(restored.params, original.params);
+    assert_eq!(restored.world_size(), original.world_size());
+    assert_eq!(restored.particles.boundary(), original.particles.boundary());
+    assert_eq!(restored.particles.domain(), original.particles.domain());
+    assert_eq!(to_bytes(&restored), to_bytes(&original));
+
+    // Including the half kick velocity Verlet still owes, or the next steps would differ
+    for _ in 0..5 {
+        original.step();
+        restored.step();
+    }
+    let positions = |simulation: &Simulation| -> Vec<Vec2> {
+        simulation.particles.iter().map(|p| p.pos).collect()
+    };
+    assert_eq!(positions(&restored), positions(&original));
+}
+
+#[test]
+fn damaged_snapshots_are_rejected() {
+    let bytes = to_bytes(&simulation());
+
+    let mut flipped = bytes.clone();
+    flipped[100] ^= 1;
+    let result = snapshot::read(&mut flipped.as_slice());
+    assert!(matches!(result, Err(SnapshotError::ChecksumMismatch)));
+
+    let truncated = &bytes[..bytes.len() - 10];
+    assert!(snapshot::read(&mut &truncated[..]).is_err());
+
+    let mut magic = bytes.clone();
+    magic[0] = b'X';
+    let result = snapshot::read(&mut magic.as_slice());
+    assert!(matches!(result, Err(SnapshotError::BadMagic)));
+
+    let mut version = bytes;
+    version[4..8].copy_from_slice(&99u32.to_le_bytes());
+    let result = snapshot::read(&mut version.as_slice());
+    assert!(matches!(result, Err(SnapshotError::UnsupportedVersion(99))));
+}
+
+// The snapshot with its world size replaced and the checksum fixed up
+fn with_world_size(mut bytes: Vec<u8>, world_size: Vec2) -> Vec<u8> {
+    bytes[16..20].copy_from_slice(&world_size.x.to_le_bytes());
+    bytes[20..24].copy_from_slice(&world_size.y.to_le_bytes());
+    let payload_end = bytes.len() - 4;
+    let checksum = crc32fast::hash(&bytes[16..payload_end]);
+    bytes[payload_end..].copy_from_slice(&checksum.to_le_bytes());
+    bytes
+}
+
+#[test]
+fn bogus_world_sizes_are_rejected() {
+    let bytes = to_bytes(&simulation());
+    for world_size in [
+        Vec2::new(f32::INFINITY, 700.),
+        Vec2::new(900., f32::NAN),
+        Vec2::new(-900., 700.),
+        Vec2::new(1e30, 1e30),
+    ] {
+        let bytes = with_world_size(bytes.clone(), world_size);
+        let result = snapshot::read(&mut bytes.as_slice());
+        assert!(
+            matches!(result, Err(SnapshotError::Corrupt(_))),
+            "{:?}",
+            world_size
+        );
+    }
+}